@@ -0,0 +1,20 @@
+#![no_main]
+
+use std::io::sink;
+use std::time::Duration;
+use libfuzzer_sys::fuzz_target;
+use cubirds::command_handler::{CommandHandler, SearchOptions};
+use cubirds::state::{CubirdsState, EXAMPLE_SETUP};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(commands) = std::str::from_utf8(data) else {
+        return;
+    };
+    let state = CubirdsState::read_setup(&mut EXAMPLE_SETUP.as_bytes(), &mut sink()).unwrap();
+    let mut handler = CommandHandler::from(state, 1, Duration::from_millis(10), SearchOptions::default());
+    for command in commands.lines() {
+        if handler.execute(command).is_ok() {
+            assert_eq!(handler.state.check_conservation(), Ok(()), "after {}", command);
+        }
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use std::io::sink;
+use libfuzzer_sys::fuzz_target;
+use cubirds::state::CubirdsState;
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = data;
+    if let Ok(state) = CubirdsState::read_setup(&mut input, &mut sink()) {
+        assert_eq!(state.check_conservation(), Ok(()));
+    }
+});
@@ -1,5 +1,7 @@
 use strum_macros::EnumIter;
 
+pub const BIRDS: usize = 8;
+
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, EnumIter)]
 pub enum Bird {
     PARROT,
@@ -20,6 +22,10 @@ pub struct BirdInfo {
 }
 
 impl Bird {
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
     pub fn from_char(character: char) -> Option<Bird> {
         match character {
             'P' => Some(Bird::PARROT),
@@ -34,7 +40,7 @@ impl Bird {
         }
     }
 
-    pub fn to_char(&self) -> char {
+    pub fn to_char(self) -> char {
         match self {
             Bird::PARROT => 'P',
             Bird::WARBLER => 'W',
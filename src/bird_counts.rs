@@ -0,0 +1,130 @@
+use std::fmt;
+use std::ops::{Add, AddAssign, Index, IndexMut, Sub, SubAssign};
+use rand::Rng;
+use strum::IntoEnumIterator;
+use crate::bird::{Bird, BIRDS};
+
+#[derive(Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct BirdCounts([i32; BIRDS]);
+
+impl BirdCounts {
+    pub fn new() -> BirdCounts {
+        BirdCounts([0; BIRDS])
+    }
+
+    /// `count` of every species.
+    pub fn uniform(count: i32) -> BirdCounts {
+        BirdCounts([count; BIRDS])
+    }
+
+    pub fn full_deck() -> BirdCounts {
+        let mut counts = BirdCounts::new();
+        for bird in Bird::iter() {
+            counts[bird] = bird.information().cards;
+        }
+        return counts;
+    }
+
+    pub fn from_birds(birds: &[Bird]) -> BirdCounts {
+        let mut counts = BirdCounts::new();
+        for bird in birds {
+            counts[*bird] += 1;
+        }
+        return counts;
+    }
+
+    pub fn total(&self) -> i32 {
+        self.0.iter().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|x| *x == 0)
+    }
+
+    pub fn species(&self) -> usize {
+        self.0.iter().filter(|x| **x > 0).count()
+    }
+
+    pub fn remove(&mut self, bird: Bird) -> i32 {
+        let count = self[bird];
+        self[bird] = 0;
+        return count;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Bird, i32)> + '_ {
+        Bird::iter().map(move |bird| (bird, self[bird])).filter(|x| x.1 > 0)
+    }
+
+    pub fn to_vec(self) -> Vec<Bird> {
+        let mut birds = Vec::with_capacity(self.total().max(0) as usize);
+        for (bird, count) in self.iter() {
+            for _ in 0..count {
+                birds.push(bird);
+            }
+        }
+        return birds;
+    }
+
+    pub fn species_choice<R: Rng>(&self, rng: &mut R) -> Option<Bird> {
+        let species = self.species();
+        if species == 0 {
+            return None;
+        }
+        let choice = rng.gen_range(0..species);
+        return self.iter().nth(choice).map(|x| x.0);
+    }
+}
+
+impl fmt::Debug for BirdCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl Index<Bird> for BirdCounts {
+    type Output = i32;
+
+    fn index(&self, bird: Bird) -> &i32 {
+        &self.0[bird.index()]
+    }
+}
+
+impl IndexMut<Bird> for BirdCounts {
+    fn index_mut(&mut self, bird: Bird) -> &mut i32 {
+        &mut self.0[bird.index()]
+    }
+}
+
+impl AddAssign for BirdCounts {
+    fn add_assign(&mut self, other: BirdCounts) {
+        for i in 0..BIRDS {
+            self.0[i] += other.0[i];
+        }
+    }
+}
+
+impl SubAssign for BirdCounts {
+    fn sub_assign(&mut self, other: BirdCounts) {
+        for i in 0..BIRDS {
+            self.0[i] -= other.0[i];
+        }
+    }
+}
+
+impl Add for BirdCounts {
+    type Output = BirdCounts;
+
+    fn add(mut self, other: BirdCounts) -> BirdCounts {
+        self += other;
+        return self;
+    }
+}
+
+impl Sub for BirdCounts {
+    type Output = BirdCounts;
+
+    fn sub(mut self, other: BirdCounts) -> BirdCounts {
+        self -= other;
+        return self;
+    }
+}
@@ -0,0 +1,868 @@
+use std::collections::HashMap;
+use std::io::stdin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::bird::Bird;
+use crate::line::Line;
+use crate::LINES;
+use crate::deal_sampler::DealSampler;
+use crate::endgame::EndgameSolver;
+use crate::evaluation::{EvaluationPool, Search, SearchPosition};
+use crate::event::GameEvent;
+use crate::state::CubirdsState;
+use crate::materialized_state::{FlockMove, LineMove, SimplifiableMove, TurnPhase};
+use crate::search_tree::{PlyMove, SearchNode};
+
+const EVALUATION_TIME: Duration = Duration::from_secs(10);
+const PONDER_TIME: Duration = Duration::from_secs(600);
+pub const REPORT_INTERVAL: Duration = Duration::from_millis(1000);
+
+struct RunningSearch {
+    search: Search,
+    reporter: thread::JoinHandle<()>,
+    /// Whether the search grows the game tree, rather than one for another seat.
+    game_tree: bool,
+}
+
+/// Switches for how the handler searches and tracks, each also set by its command.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchOptions {
+    /// Whether to search while an opponent is to move.
+    pub ponder: bool,
+    /// Whether opponents who end a turn without flying are taken to have no flock to fly.
+    pub infer_flocks: bool,
+    /// Whether rollouts are spread over the first plies by how promising they look.
+    pub adaptive: bool,
+}
+
+pub struct CommandHandler {
+    pub state: CubirdsState,
+    prev_state: Option<(CubirdsState, TurnPhase)>,
+    pool: EvaluationPool,
+    report_interval: Duration,
+    search: Option<RunningSearch>,
+    options: SearchOptions,
+    ponder: Option<Search>,
+    tree: Option<SearchNode>,
+    /// Kept between searches so positions already solved are looked up.
+    endgame: Arc<Mutex<EndgameSolver>>,
+    /// How far `state.turn` is into their turn.
+    phase: TurnPhase,
+}
+
+impl CommandHandler {
+    pub fn from(state: CubirdsState, threads: usize, report_interval: Duration, options: SearchOptions) -> CommandHandler {
+        let handler = CommandHandler {
+            state,
+            prev_state: None,
+            pool: EvaluationPool::new(threads),
+            report_interval,
+            search: None,
+            options,
+            ponder: None,
+            tree: None,
+            endgame: Arc::new(Mutex::new(EndgameSolver::new())),
+            phase: TurnPhase::Play,
+        };
+        return handler;
+    }
+
+    /// Closes the observed turn, returning the moves it implied: a play that was
+    /// still waiting on its draw decision, and a flock that was never flown. Only if
+    /// `declined`, the turn was seen to end without a flock, does that cap the
+    /// player's hand.
+    fn end_observed_turn(&mut self, declined: bool) -> Vec<PlyMove> {
+        let plies = match std::mem::replace(&mut self.phase, TurnPhase::Play) {
+            TurnPhase::Play => return Vec::new(),
+            TurnPhase::Draw(line_move) => vec![PlyMove::Line(line_move), PlyMove::Flock(FlockMove{bird: None})],
+            TurnPhase::Flock => vec![PlyMove::Flock(FlockMove{bird: None})],
+        };
+        if declined && self.options.infer_flocks && self.state.turn != self.state.player_idx as usize {
+            self.state.decline_flocks(self.state.turn);
+        }
+        self.state.turn = (self.state.turn + 1) % self.state.players.len();
+        return plies;
+    }
+
+    /// Describes how `event` breaks the turn order, if it does: a play must come from
+    /// the player whose turn is starting, and a draw or flock from the one who just played.
+    fn turn_warning(&self, event: &GameEvent) -> Option<String> {
+        let turn = self.state.turn;
+        let next = (turn + 1) % self.state.players.len();
+        match *event {
+            GameEvent::Play{player, ..} => {
+                let expected = if self.phase == TurnPhase::Play { turn } else { next };
+                if player != expected {
+                    return Some(format!("Player {} played out of turn, expected player {}.", player, expected));
+                }
+            },
+            GameEvent::Draw{player, ..} | GameEvent::Fly{player, ..} if player != turn => {
+                return Some(format!("Player {} acted during player {}'s turn.", player, turn));
+            },
+            GameEvent::Draw{player, ..} if !matches!(self.phase, TurnPhase::Draw(_)) => {
+                return Some(format!("Player {} can only draw right after a play without a sandwich.", player));
+            },
+            GameEvent::Fly{player, ..} if self.phase == TurnPhase::Play => {
+                return Some(format!("Player {} flew a flock before playing.", player));
+            },
+            _ => {},
+        }
+        return None;
+    }
+
+    /// Stops whichever search holds the game tree and takes it. The tree is rooted
+    /// at the observed position: `state.turn` to act, `phase` into their turn.
+    fn take_tree(&mut self) -> SearchNode {
+        self.stop_search();
+        if let Some(ponder) = self.ponder.take() {
+            return ponder.finish();
+        }
+        return self.tree.take().unwrap_or_default();
+    }
+
+    /// Descends the game tree past the moves just observed, keeping the statistics
+    /// gathered for the position they lead to.
+    fn advance_tree(&mut self, plies: Vec<PlyMove>) {
+        self.tree = Some(self.take_tree().descend(&plies));
+        self.start_ponder();
+    }
+
+    fn discard_tree(&mut self) {
+        let _ = self.take_tree();
+    }
+
+    fn observed_position(&self) -> SearchPosition {
+        return SearchPosition{
+            state: self.state.clone(),
+            phase: self.phase.clone(),
+        };
+    }
+
+    fn start_ponder(&mut self) {
+        let opponent_to_move = self.state.turn != self.state.player_idx as usize;
+        let waiting = self.state.pending_refill.is_some() || self.state.pending_hand;
+        if !self.options.ponder || !opponent_to_move || waiting || self.search.is_some() || self.ponder.is_some() {
+            return;
+        }
+        let position = self.observed_position();
+        if let Ok(sampler) = DealSampler::new(&position.state) {
+            let tree = self.tree.take().unwrap_or_default();
+            self.ponder = Some(self.pool.start(position, sampler, tree, PONDER_TIME, self.options.adaptive, None));
+        }
+    }
+
+    /// Applies an event to the tracked state and moves the game tree past it. Events
+    /// the state rejects, or that would track more cards than exist, change nothing.
+    /// Events out of turn are accepted with a warning, and the turn follows them.
+    /// A flock that empties its player's hand ends the round and redeals.
+    fn observe(&mut self, event: GameEvent) -> Result<(), String> {
+        let emptied = self.state.players.get(self.state.turn).is_some_and(|x| x.cards.total_cards == 0);
+        if let (GameEvent::Play{..}, true) = (&event, emptied && self.phase != TurnPhase::Play) {
+            return Err(format!("Player {} has no cards left, END their turn to start the next round.", self.state.turn));
+        }
+        let warning = self.turn_warning(&event);
+        let mut state = self.state.clone();
+        let sandwiched = state.apply(&event)?;
+        let round_over = match event {
+            GameEvent::Fly{player, ..} => state.players[player].cards.total_cards == 0,
+            _ => false,
+        };
+        if round_over {
+            state.apply(&GameEvent::Reset)?;
+        }
+        state.check_conservation()?;
+        self.state = state;
+        if let Some(warning) = warning {
+            println!("{}", warning);
+        }
+
+        match event {
+            GameEvent::Play{player, bird, line, left, ..} => {
+                let mut plies = self.end_observed_turn(true);
+                if player != self.state.turn {
+                    self.discard_tree();
+                    plies.clear();
+                    self.state.turn = player;
+                }
+                let line_move = LineMove{
+                    line,
+                    bird,
+                    left,
+                    draw: false,
+                };
+                if sandwiched {
+                    plies.push(PlyMove::Line(line_move));
+                    self.phase = TurnPhase::Flock;
+                } else {
+                    self.phase = TurnPhase::Draw(line_move);
+                }
+                self.advance_tree(plies);
+            },
+            GameEvent::Draw{player, ..} => {
+                let mut plies = Vec::new();
+                match &self.phase {
+                    TurnPhase::Draw(line_move) if player == self.state.turn => {
+                        plies.push(PlyMove::Line(LineMove{draw: true, ..line_move.clone()}));
+                    },
+                    _ => self.discard_tree(),
+                }
+                self.state.turn = player;
+                self.phase = TurnPhase::Flock;
+                self.advance_tree(plies);
+            },
+            GameEvent::Fly{player, bird, ..} => {
+                let mut plies = Vec::new();
+                if player != self.state.turn || self.phase == TurnPhase::Play {
+                    self.discard_tree();
+                } else {
+                    if let TurnPhase::Draw(line_move) = &self.phase {
+                        plies.push(PlyMove::Line(line_move.clone()));
+                    }
+                    plies.push(PlyMove::Flock(FlockMove{bird: Some(bird)}));
+                }
+                self.phase = TurnPhase::Play;
+                self.state.turn = (player + 1) % self.state.players.len();
+                self.advance_tree(plies);
+            },
+            GameEvent::Refill{..} => self.advance_tree(Vec::new()),
+            GameEvent::Reset => {
+                self.end_observed_turn(false);
+                self.discard_tree();
+            },
+            GameEvent::Hand{..} => {
+                self.discard_tree();
+                self.start_ponder();
+            },
+        }
+        if round_over || event == GameEvent::Reset {
+            self.discard_tree();
+            println!("New round, player {} to play. Enter your hand: SET {} <birds>", self.state.turn, self.state.player_idx);
+        }
+        return Ok(());
+    }
+
+    fn handle_play(&mut self, components: Vec<&str>) -> Result<(), String> {
+        let pnum = parse_argument(&components, 1)?;
+        let bird = parse_bird(&components, 2)?;
+        let count = parse_argument(&components, 3)?;
+        let line = parse_argument(&components, 4)?;
+        let play_dir = parse_direction(&components, 5)?;
+        let mut new: Option<(Vec<Bird>, bool)> = None;
+        if components.len() > 6 {
+            let new_bird = parse_birds(&components, 6)?;
+            let new_dir = parse_direction(&components, 7)?;
+            new = Some((new_bird, new_dir));
+        }
+        self.observe(GameEvent::Play{player: pnum, bird, count, line, left: play_dir, refill: new})?;
+        if let Some(line) = self.state.pending_refill {
+            println!("Line {} needs a refill: REFILL {} <birds> <L|R>", line, line);
+        }
+        return Ok(());
+    }
+
+    fn handle_refill(&mut self, components: Vec<&str>) -> Result<(), String> {
+        let line = parse_argument(&components, 1)?;
+        let birds = parse_birds(&components, 2)?;
+        let left = parse_direction(&components, 3)?;
+        return self.observe(GameEvent::Refill{line, birds, left});
+    }
+
+    fn handle_draw(&mut self, components: Vec<&str>) -> Result<(), String> {
+        let pnum = parse_argument(&components, 1)?;
+        let mut new_birds: Option<(Bird, Bird)> = None;
+        if components.len() > 2 {
+            let bird1 = parse_bird(&components, 2)?;
+            let bird2 = parse_bird(&components, 3)?;
+            new_birds = Some((bird1, bird2));
+        }
+        return self.observe(GameEvent::Draw{player: pnum, birds: new_birds});
+    }
+
+    fn handle_fly(&mut self, components: Vec<&str>) -> Result<(), String> {
+        let pnum = parse_argument(&components, 1)?;
+        let bird = parse_bird(&components, 2)?;
+        let hand_size = parse_argument(&components, 3)?;
+        let flock_small = match components.get(4) {
+            Some(&"SMALL") => true,
+            Some(&"LARGE") => false,
+            _ => return Err(String::from("Flock size must be SMALL or LARGE.")),
+        };
+        return self.observe(GameEvent::Fly{player: pnum, bird, hand_size, small: flock_small});
+    }
+
+    fn handle_set(&mut self, components: Vec<&str>) -> Result<(), String> {
+        let pnum = parse_argument(&components, 1)?;
+        let birds = parse_birds(&components, 2)?;
+        return self.observe(GameEvent::Hand{player: pnum, birds});
+    }
+
+    fn handle_reset(&mut self) -> Result<(), String> {
+        return self.observe(GameEvent::Reset);
+    }
+
+    /// Ends the current turn without a draw or flock, passing play to the next player.
+    /// A turn ending with an empty hand ends the round.
+    fn handle_end(&mut self) -> Result<(), String> {
+        if let Some(line) = self.state.pending_refill {
+            return Err(format!("Line {} must be refilled first.", line));
+        }
+        if self.phase == TurnPhase::Play {
+            return Err(format!("Player {} hasn't played yet.", self.state.turn));
+        }
+        if self.state.players[self.state.turn].cards.total_cards == 0 {
+            return self.observe(GameEvent::Reset);
+        }
+        let plies = self.end_observed_turn(true);
+        self.advance_tree(plies);
+        println!("Player {} to play.", self.state.turn);
+        return Ok(());
+    }
+
+    /// Evaluates our plays. Away from the start of our turn the position is taken as
+    /// if it were, leaving the tracked game and its tree alone.
+    fn handle_play_score(&mut self, components: Vec<&str>) {
+        let player_idx = self.state.player_idx as usize;
+        let mut position = self.observed_position();
+        if position.state.turn == player_idx && position.phase == TurnPhase::Play {
+            let tree = self.take_tree();
+            self.start_search(position, tree, components.get(1), true);
+            return;
+        }
+        println!("It isn't the start of your turn, evaluating as if it were.");
+        self.tree = Some(self.take_tree());
+        position.state.turn = player_idx;
+        position.phase = TurnPhase::Play;
+        self.start_search(position, SearchNode::new(), components.get(1), false);
+    }
+
+    /// Evaluates our flocks, after declining to draw if we still could. The tracked
+    /// game and its tree are left alone.
+    fn handle_flock_score(&mut self, components: Vec<&str>) {
+        let player_idx = self.state.player_idx as usize;
+        let mut position = self.observed_position();
+        if position.state.turn == player_idx && position.phase == TurnPhase::Flock {
+            let tree = self.take_tree();
+            self.start_search(position, tree, components.get(1), true);
+            return;
+        }
+        let game_tree = self.take_tree();
+        let mut tree = SearchNode::new();
+        if let (true, TurnPhase::Draw(line_move)) = (position.state.turn == player_idx, &position.phase) {
+            tree = game_tree.children.get(&PlyMove::Line(line_move.clone())).cloned().unwrap_or_default();
+        } else {
+            println!("You haven't played this turn, evaluating your flock anyway.");
+        }
+        self.tree = Some(game_tree);
+        position.state.turn = player_idx;
+        position.phase = TurnPhase::Flock;
+        self.start_search(position, tree, components.get(1), false);
+    }
+
+    /// Searches for `seat` as if their turn were starting, knowing only what they
+    /// could: public cards and their own hand. The game tree is left alone.
+    fn handle_coach(&mut self, components: Vec<&str>) -> Result<(), String> {
+        let seat: usize = parse_argument(&components, 1)?;
+        if seat >= self.state.players.len() {
+            return Err(format!("There is no player {}.", seat));
+        }
+        self.tree = Some(self.take_tree());
+
+        let mut state = self.state.perspective(seat);
+        state.turn = seat;
+        let position = SearchPosition{
+            state,
+            phase: TurnPhase::Play,
+        };
+        self.start_search(position, SearchNode::new(), components.get(2), false);
+        return Ok(());
+    }
+
+    /// Searches `position` in the background, printing the current top moves every
+    /// report interval and the final result once it finishes or is stopped.
+    fn start_search(&mut self, position: SearchPosition, tree: SearchNode, seconds: Option<&&str>, game_tree: bool) {
+        let duration = seconds
+            .and_then(|x| u64::from_str(x).ok())
+            .map(Duration::from_secs)
+            .unwrap_or(EVALUATION_TIME)
+            .min(PONDER_TIME);
+        let waiting = match self.state.pending_refill {
+            Some(line) => Some(format!("Line {} must be refilled first.", line)),
+            None if self.state.pending_hand => Some(String::from("Enter your new hand first.")),
+            None => None,
+        };
+        if let Some(waiting) = waiting {
+            println!("{}", waiting);
+            if game_tree {
+                self.tree = Some(tree);
+            }
+            return;
+        }
+        let sampler = match DealSampler::new(&position.state) {
+            Ok(sampler) => sampler,
+            Err(error) => {
+                println!("Can't evaluate: {}", error);
+                if game_tree {
+                    self.tree = Some(tree);
+                }
+                return;
+            },
+        };
+        if let Some(relaxed) = sampler.relaxed() {
+            println!("Not every card bound is respected: {}", relaxed);
+        }
+        let player_idx = position.state.player_idx as usize;
+        let us = self.state.player_idx as usize;
+        // Placements are only interchangeable before any birds are placed.
+        let board = (position.phase == TurnPhase::Play).then(|| position.state.board.clone());
+        if let Some(deals) = sampler.exact_deals() {
+            println!("Rolling out every play on all {} consistent deals, weighted by their probability.", deals);
+        }
+        let search = self.pool.start(position, sampler, tree, duration, self.options.adaptive, Some(Arc::clone(&self.endgame)));
+        let interval = self.report_interval;
+        let threads = self.pool.size();
+        let players = self.state.players.len();
+
+        let reporter_search = search.clone();
+        let reporter = thread::spawn(move || {
+            let started = Instant::now();
+            while !reporter_search.wait_timeout(interval) {
+                print!("[{:.1}s] ", started.elapsed().as_secs_f64());
+                CommandHandler::print_scores(reporter_search.with_tree(|x| x.child_scores()), board.as_ref(), players, player_idx, us);
+            }
+            if let Some(solved) = reporter_search.solved() {
+                print!("solved exactly: ");
+                let rates = CommandHandler::group_equivalent(solved, board.as_ref())
+                    .into_iter()
+                    .map(|(name, chances)| (name, chances[0].iter().map(|x| 100.0 * x).collect()))
+                    .collect();
+                CommandHandler::print_rates(rates, player_idx, us);
+                return;
+            }
+            print!("done on {} threads: ", threads);
+            CommandHandler::print_scores(reporter_search.with_tree(|x| x.child_scores()), board.as_ref(), players, player_idx, us);
+        });
+
+        self.search = Some(RunningSearch{search, reporter, game_tree});
+    }
+
+    fn stop_search(&mut self) -> bool {
+        if let Some(running) = self.search.take() {
+            running.search.stop();
+            let _ = running.reporter.join();
+            let tree = running.search.finish();
+            if running.game_tree {
+                self.tree = Some(tree);
+            }
+            return true;
+        }
+        return false;
+    }
+
+    /// Waits for the running search to end, printing its last report.
+    pub fn wait_search(&mut self) {
+        if let Some(running) = &self.search {
+            while !running.search.wait_timeout(self.report_interval) {}
+        }
+        self.stop_search();
+    }
+
+    fn handle_stop(&mut self) {
+        if !self.stop_search() {
+            println!("No search running.");
+        }
+        self.start_ponder();
+    }
+
+    /// Gathers the moves that reach the same position on `board`, named together like
+    /// "R0L=R2L". Without a board every move stands alone.
+    fn group_equivalent<V>(moves: HashMap<PlyMove, V>, board: Option<&[Line; LINES]>) -> Vec<(String, Vec<V>)> {
+        let mut groups: HashMap<PlyMove, (Vec<String>, Vec<V>)> = HashMap::new();
+        for (ply, value) in moves {
+            let key = match (&ply, board) {
+                (PlyMove::Line(line_move), Some(board)) => PlyMove::Line(line_move.canonical(board)),
+                _ => ply.clone(),
+            };
+            let group = groups.entry(key).or_insert_with(|| (Vec::new(), Vec::new()));
+            group.0.push(ply.simplified());
+            group.1.push(value);
+        }
+        return groups.into_values()
+            .map(|(mut names, values)| {
+                names.sort();
+                return (names.join("="), values);
+            })
+            .collect();
+    }
+
+    /// Prints the five moves that win most often for `player_idx`, each with how often
+    /// every player ends up winning after it. Moves equivalent on `board` are counted
+    /// together. Seat `us` is shown as "you".
+    fn print_scores(move_scores: HashMap<PlyMove, (Vec<f64>, f64, i32)>, board: Option<&[Line; LINES]>, players: usize, player_idx: usize, us: usize) {
+        let mut scores = Vec::new();
+        let mut total = 0;
+        for (name, members) in CommandHandler::group_equivalent(move_scores, board) {
+            let weight: f64 = members.iter().map(|x| x.1).sum();
+            let rates: Vec<f64> = (0..players)
+                .map(|x| 100.0 * members.iter().map(|y| y.0.get(x).copied().unwrap_or(0.0)).sum::<f64>() / weight)
+                .collect();
+            total += members.iter().map(|x| x.2).sum::<i32>();
+            scores.push((name, rates));
+        }
+        print!("evaluated {} ", total);
+        CommandHandler::print_rates(scores, player_idx, us);
+    }
+
+    /// Prints the five moves with the best win rate for `player_idx`, given in percent
+    /// for every player.
+    fn print_rates(mut scores: Vec<(String, Vec<f64>)>, player_idx: usize, us: usize) {
+        scores.sort_by(|x, y| y.1[player_idx].partial_cmp(&x.1[player_idx]).unwrap());
+        for (fmove, rates) in scores.iter().take(5) {
+            let outcomes: Vec<String> = rates.iter().enumerate()
+                .map(|(player, rate)| if player == us { format!("you {:.1}%", rate) } else { format!("P{} {:.1}%", player, rate) })
+                .collect();
+            print!("{} ({}) ", fmove, outcomes.join(", "));
+        }
+        println!();
+    }
+
+    fn handle_ponder(&mut self, components: Vec<&str>) {
+        match components.get(1) {
+            Some(&"ON") => {
+                self.options.ponder = true;
+                self.start_ponder();
+            },
+            Some(&"OFF") => {
+                self.options.ponder = false;
+                self.tree = Some(self.take_tree());
+            },
+            _ => {},
+        }
+        if let Some(ponder) = &self.ponder {
+            let pondered = ponder.with_tree(|x| x.visits);
+            println!("Pondering, {} rollouts so far.", pondered);
+        } else {
+            println!("Ponder {}.", if self.options.ponder { "on" } else { "off" });
+        }
+    }
+
+    fn handle_infer(&mut self, components: Vec<&str>) {
+        match components.get(1) {
+            Some(&"ON") => self.options.infer_flocks = true,
+            Some(&"OFF") => self.options.infer_flocks = false,
+            _ => {},
+        }
+        println!("Flock inference {}.", if self.options.infer_flocks { "on" } else { "off" });
+    }
+
+    fn handle_adaptive(&mut self, components: Vec<&str>) {
+        match components.get(1) {
+            Some(&"ON") => self.options.adaptive = true,
+            Some(&"OFF") => self.options.adaptive = false,
+            _ => {},
+        }
+        println!("Adaptive rollouts {}.", if self.options.adaptive { "on" } else { "off" });
+    }
+
+    fn handle_undo(&mut self) -> Result<(), String> {
+        let (prev_state, prev_phase) = self.prev_state.take().ok_or("Nothing to undo.")?;
+        self.discard_tree();
+        self.state = prev_state;
+        self.phase = prev_phase;
+        return Ok(());
+    }
+
+    /// How far the player to move is into their turn.
+    pub fn phase(&self) -> &TurnPhase {
+        return &self.phase;
+    }
+
+    /// Applies an event as the event commands do, stopping any search first.
+    pub fn submit(&mut self, event: GameEvent) -> Result<(), String> {
+        let previous = (self.state.clone(), self.phase.clone());
+        self.stop_search();
+        self.observe(event)?;
+        self.prev_state = Some(previous);
+        return Ok(());
+    }
+
+    /// Ends the current turn as END does.
+    pub fn end_turn(&mut self) -> Result<(), String> {
+        let previous = (self.state.clone(), self.phase.clone());
+        self.stop_search();
+        self.handle_end()?;
+        self.prev_state = Some(previous);
+        return Ok(());
+    }
+
+    pub fn evaluate_command(&mut self) {
+        let mut input = String::new();
+        let _ = stdin().read_line(&mut input);
+        input.pop();
+
+        if let Err(error) = self.execute(&input) {
+            println!("{}", error);
+        }
+    }
+
+    /// Runs one command. Rejected commands leave the tracked state untouched.
+    pub fn execute(&mut self, input: &str) -> Result<(), String> {
+        let previous = (self.state.clone(), self.phase.clone());
+        let components: Vec<&str> = input.split(" ").collect();
+
+        if ["PLAY", "REFILL", "DRAW", "FLY", "END", "SET", "RESET", "UNDO"].contains(&components[0]) {
+            self.stop_search();
+        }
+
+        match components[0] {
+            "PLAY" => self.handle_play(components)?,
+            "REFILL" => self.handle_refill(components)?,
+            "DRAW" => self.handle_draw(components)?,
+            "FLY" => self.handle_fly(components)?,
+            "END" => self.handle_end()?,
+            "SET" => self.handle_set(components)?,
+            "RESET" => self.handle_reset()?,
+            "PLAYSCORE" => self.handle_play_score(components),
+            "FLOCKSCORE" => self.handle_flock_score(components),
+            "COACH" => self.handle_coach(components)?,
+            "STOP" => self.handle_stop(),
+            "PONDER" => {
+                self.handle_ponder(components);
+                return Ok(());
+            },
+            "INFER" => {
+                self.handle_infer(components);
+                return Ok(());
+            },
+            "ADAPTIVE" => {
+                self.handle_adaptive(components);
+                return Ok(());
+            },
+            "UNDO" => self.handle_undo()?,
+            "PRINT" => {
+                println!("{:?}", self.state);
+                return Ok(());
+            },
+            _ => return Err(String::from("Invalid command.")),
+        }
+
+        self.prev_state = Some(previous);
+        return Ok(());
+    }
+}
+
+impl Drop for CommandHandler {
+    fn drop(&mut self) {
+        self.discard_tree();
+    }
+}
+
+fn parse_argument<T: FromStr>(components: &[&str], idx: usize) -> Result<T, String> {
+    let component = components.get(idx).ok_or_else(|| format!("Missing argument {}.", idx))?;
+    return T::from_str(component).map_err(|_| format!("Invalid argument '{}'.", component));
+}
+
+fn parse_bird(components: &[&str], idx: usize) -> Result<Bird, String> {
+    let component = components.get(idx).ok_or_else(|| format!("Missing argument {}.", idx))?;
+    return component.chars().next()
+        .and_then(Bird::from_char)
+        .ok_or_else(|| format!("Invalid bird '{}'.", component));
+}
+
+fn parse_birds(components: &[&str], idx: usize) -> Result<Vec<Bird>, String> {
+    let component = components.get(idx).ok_or_else(|| format!("Missing argument {}.", idx))?;
+    return Bird::from_string(component).ok_or_else(|| format!("Invalid birds '{}'.", component));
+}
+
+fn parse_direction(components: &[&str], idx: usize) -> Result<bool, String> {
+    match components.get(idx) {
+        Some(&"L") => Ok(true),
+        Some(&"R") => Ok(false),
+        _ => Err(String::from("Direction must be L or R.")),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::sink;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use strum::IntoEnumIterator;
+    use crate::bird_counts::BirdCounts;
+    use crate::state::EXAMPLE_SETUP;
+    use crate::STARTING_CARDS_HAND;
+    use super::*;
+
+    /// A handler for `EXAMPLE_SETUP`, reporting every 10ms on one thread.
+    pub(crate) fn handler(options: SearchOptions) -> CommandHandler {
+        let state = CubirdsState::read_setup(&mut EXAMPLE_SETUP.as_bytes(), &mut sink()).unwrap();
+        return CommandHandler::from(state, 1, Duration::from_millis(10), options);
+    }
+
+    fn random_token(rng: &mut StdRng) -> String {
+        let tokens = ["0", "1", "2", "3", "5", "-1", "99999999999", "P", "R", "W", "O", "RR", "PWT", "X", "L", "R", "SMALL", "LARGE", ""];
+        return tokens.choose(rng).unwrap().to_string();
+    }
+
+    fn random_command(rng: &mut StdRng) -> String {
+        let commands = ["PLAY", "REFILL", "DRAW", "FLY", "END", "SET", "RESET", "PLAYSCORE", "FLOCKSCORE", "COACH", "STOP", "PONDER", "INFER", "ADAPTIVE", "UNDO", "PRINT", "JUMP", ""];
+        let mut components = vec![commands.choose(rng).unwrap().to_string()];
+        for _ in 0..rng.gen_range(0..9) {
+            components.push(random_token(rng));
+        }
+        if components[0].ends_with("SCORE") {
+            components.truncate(1);
+            components.push(String::from("0"));
+        } else if components[0] == "COACH" {
+            components.truncate(2);
+            components.push(String::from("0"));
+        }
+        return components.join(" ");
+    }
+
+    #[test]
+    fn random_commands_keep_cards_conserved() {
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut handler = handler(SearchOptions{infer_flocks: seed % 2 == 0, adaptive: seed % 3 == 0, ..SearchOptions::default()});
+            for _ in 0..300 {
+                let command = random_command(&mut rng);
+                if handler.execute(&command).is_ok() {
+                    assert_eq!(handler.state.check_conservation(), Ok(()), "after {}", command);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejected_commands_change_nothing() {
+        let mut handler = handler(SearchOptions::default());
+        for command in ["PLAY", "PLAY 7 R 1 0 L", "PLAY 1 R 9 0 L", "PLAY 1 R 1 4 L", "PLAY 1 R 1 0 X", "FLY 1 R 7 SMALL", "FLY 1 O 4 SMALL", "DRAW 0 R", "SET 0 PPPPPPPPPPPPPP", "UNDO"] {
+            assert!(handler.execute(command).is_err(), "{} was accepted", command);
+        }
+        assert_eq!(handler.state.players[1].cards.max_cards, BirdCounts::uniform(STARTING_CARDS_HAND));
+        assert_eq!(handler.state.players[1].cards.total_cards, STARTING_CARDS_HAND);
+    }
+
+    #[test]
+    fn turns_follow_the_events() {
+        let mut handler = handler(SearchOptions::default());
+        let play = |player| GameEvent::Play{player, bird: Bird::ROBIN, count: 1, line: 0, left: true, refill: None};
+        assert_eq!(handler.turn_warning(&play(1)), None);
+        assert!(handler.turn_warning(&play(2)).is_some());
+        assert!(handler.execute("END").is_err());
+
+        handler.execute("PLAY 1 R 1 0 L").unwrap();
+        assert_eq!(handler.state.turn, 1);
+        assert!(handler.turn_warning(&play(1)).is_some());
+        assert_eq!(handler.turn_warning(&play(2)), None);
+        assert!(handler.turn_warning(&GameEvent::Draw{player: 2, birds: None}).is_some());
+        assert_eq!(handler.turn_warning(&GameEvent::Draw{player: 1, birds: None}), None);
+
+        handler.execute("DRAW 1").unwrap();
+        assert!(handler.turn_warning(&GameEvent::Draw{player: 1, birds: None}).is_some());
+        handler.execute("END").unwrap();
+        assert_eq!((handler.state.turn, &handler.phase), (2, &TurnPhase::Play));
+
+        handler.execute("PLAY 0 W 1 3 R").unwrap();
+        assert_eq!(handler.state.turn, 0);
+        handler.execute("UNDO").unwrap();
+        assert_eq!((handler.state.turn, &handler.phase), (2, &TurnPhase::Play));
+    }
+
+    #[test]
+    fn score_queries_leave_the_game_alone() {
+        let mut handler = handler(SearchOptions{infer_flocks: true, ..SearchOptions::default()});
+        handler.execute("PLAY 1 R 1 0 L").unwrap();
+        let observed = (handler.state.clone(), handler.phase.clone());
+        for command in ["PLAYSCORE 0", "FLOCKSCORE 0", "STOP"] {
+            handler.execute(command).unwrap();
+            assert_eq!((&handler.state, &handler.phase), (&observed.0, &observed.1), "{} changed the game", command);
+        }
+        assert_eq!(handler.turn_warning(&GameEvent::Draw{player: 1, birds: None}), None);
+        handler.execute("DRAW 1").unwrap();
+        handler.execute("END").unwrap();
+        assert_eq!((handler.state.turn, &handler.phase), (2, &TurnPhase::Play));
+    }
+
+    #[test]
+    fn declined_flocks_cap_opponent_hands() {
+        let mut handler = handler(SearchOptions{infer_flocks: true, ..SearchOptions::default()});
+        handler.execute("PLAY 1 R 1 0 L").unwrap();
+        handler.execute("END").unwrap();
+        let caps = handler.state.players[1].cards.max_cards;
+        assert!(Bird::iter().all(|x| caps[x] < x.information().small));
+
+        handler.execute("PLAY 2 W 1 3 R").unwrap();
+        handler.execute("DRAW 2").unwrap();
+        handler.execute("INFER OFF").unwrap();
+        handler.execute("END").unwrap();
+        let cards = &handler.state.players[2].cards;
+        assert!(Bird::iter().filter(|x| *x != Bird::WARBLER).all(|x| cards.max_cards[x] >= cards.total_cards));
+
+        let sampler = DealSampler::new(&handler.state).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let hand = sampler.sample(&mut rng).hands[1];
+            assert!(Bird::iter().all(|x| hand[x] <= caps[x]));
+        }
+    }
+
+    #[test]
+    fn flocks_are_only_declined_at_an_observed_end() {
+        let mut handler = handler(SearchOptions{infer_flocks: true, ..SearchOptions::default()});
+        handler.execute("PLAY 1 R 1 0 L").unwrap();
+        let bounds = handler.state.players[1].cards.clone();
+        handler.execute("PLAYSCORE 0").unwrap();
+        handler.execute("STOP").unwrap();
+        assert_eq!(handler.state.players[1].cards, bounds);
+
+        handler.execute("RESET").unwrap();
+        assert_eq!(handler.state.players[1].cards.max_cards, BirdCounts::uniform(STARTING_CARDS_HAND));
+    }
+
+    #[test]
+    fn empty_hands_end_the_round() {
+        let mut handler = handler(SearchOptions::default());
+        handler.execute("PLAY 1 R 8 0 L").unwrap();
+        assert!(handler.execute("PLAY 2 W 1 3 R").is_err());
+        handler.execute("END").unwrap();
+        assert!(handler.state.pending_hand);
+        assert_eq!(handler.state.turn, 2);
+        assert_eq!(handler.state.discard_pile.iter().map(|x| x.total_cards).sum::<i32>(), 2 * STARTING_CARDS_HAND);
+        for player in &handler.state.players {
+            assert_eq!((player.cards.known_cards.total(), player.cards.total_cards), (0, STARTING_CARDS_HAND));
+        }
+
+        for command in ["PLAY 2 W 1 3 R", "END", "RESET", "SET 0 PPRROFW"] {
+            assert!(handler.execute(command).is_err(), "{} was accepted", command);
+        }
+        handler.execute("SET 0 PPRROFWM").unwrap();
+        handler.execute("PLAY 2 W 1 3 R").unwrap();
+
+        let mut handler = tests::handler(SearchOptions::default());
+        handler.execute("PLAY 1 R 1 0 L").unwrap();
+        handler.execute("FLY 1 R 0 SMALL").unwrap();
+        assert!(handler.state.pending_hand);
+        assert_eq!(handler.state.check_conservation(), Ok(()));
+    }
+
+    #[test]
+    fn groups_moves_reaching_the_same_position() {
+        let board = ["PWR", "RWP", "OOD", "MWW"].map(|x| Line::from_birds(&Bird::from_string(x).unwrap()));
+        let play = |line: usize, left: bool| PlyMove::Line(LineMove{line, bird: Bird::OWL, left, draw: false});
+        let moves = HashMap::from([(play(0, true), 1), (play(1, false), 2), (play(2, true), 3), (play(0, false), 4)]);
+
+        let mut groups = CommandHandler::group_equivalent(moves.clone(), Some(&board));
+        groups.iter_mut().for_each(|x| x.1.sort());
+        groups.sort();
+        assert_eq!(groups, vec![
+            (String::from("O0LP=O1RP"), vec![1, 2]),
+            (String::from("O0RP"), vec![4]),
+            (String::from("O2LP"), vec![3]),
+        ]);
+        assert_eq!(CommandHandler::group_equivalent(moves, None).len(), 4);
+    }
+}
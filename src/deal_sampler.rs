@@ -0,0 +1,850 @@
+use std::collections::HashMap;
+use rand::Rng;
+use rand::seq::SliceRandom;
+use strum::IntoEnumIterator;
+use crate::bird::{Bird, BIRDS};
+use crate::bird_counts::BirdCounts;
+use crate::partial_cards::PartialCards;
+use crate::state::CubirdsState;
+
+/// Largest number of capacity combinations the counting tables may span.
+const MAX_TABLE_SIZE: usize = 1 << 16;
+/// Most partial counts kept to draw each group's share directly. Past this the
+/// shares are found by going through every combination instead.
+const MAX_STAGE_ENTRIES: usize = 1 << 22;
+/// Most consistent deals enumerated outright.
+const MAX_ENUMERATED_DEALS: usize = 1 << 12;
+
+/// The hands, discarded cards and deck composition of a deal.
+type DealKey = (Vec<BirdCounts>, BirdCounts, BirdCounts);
+/// The holders sharing one pool of cards, with how many cards each holds; `None` is the deck.
+type Pool = Vec<(Option<Holder>, i32)>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Holder {
+    Player(usize),
+    Discard,
+}
+
+/// How much of the tracked bounds a sampler respects, tightest first.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+enum Bounds {
+    All,
+    /// Only species ruled out entirely.
+    Exclusions,
+    Totals,
+}
+
+impl Bounds {
+    fn looser(&self) -> Bounds {
+        match self {
+            Bounds::All => Bounds::Exclusions,
+            _ => Bounds::Totals,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Bounds::All => "every bound",
+            Bounds::Exclusions => "only the species ruled out",
+            Bounds::Totals => "only the totals",
+        }
+    }
+}
+
+/// The hidden cards of one holder, with bounds on how many of each species they hold.
+#[derive(Debug, Clone, Copy)]
+struct Hidden {
+    holder: Holder,
+    count: i32,
+    lower: BirdCounts,
+    upper: BirdCounts,
+}
+
+impl Hidden {
+    /// What the tracked cards leave unknown, with their bounds shifted past the known cards.
+    fn from(holder: Holder, cards: &PartialCards, bounds: Bounds) -> Hidden {
+        let count = cards.total_cards - cards.known_cards.total();
+        let mut hidden = Hidden{
+            holder,
+            count,
+            lower: BirdCounts::new(),
+            upper: BirdCounts::uniform(count),
+        };
+        for bird in Bird::iter().filter(|_| bounds != Bounds::Totals) {
+            let upper = (cards.max_cards[bird] - cards.known_cards[bird]).clamp(0, count);
+            if bounds == Bounds::All {
+                hidden.lower[bird] = (cards.min_cards[bird] - cards.known_cards[bird]).clamp(0, count);
+                hidden.upper[bird] = upper;
+            } else if upper == 0 {
+                hidden.upper[bird] = 0;
+            }
+        }
+        return hidden;
+    }
+
+    /// The species ruled out entirely, if that is all the bounds say.
+    fn exclusion_mask(&self) -> Option<u8> {
+        let mut mask = 0;
+        for bird in Bird::iter() {
+            if self.lower[bird] > 0 || (self.upper[bird] > 0 && self.upper[bird] < self.count) {
+                return None;
+            }
+            if self.upper[bird] == 0 {
+                mask |= 1 << bird.index();
+            }
+        }
+        return Some(mask);
+    }
+}
+
+/// Hidden cards constrained together. Holders that only rule out the same species
+/// share a group, whose cards are split between the members uniformly since only
+/// the group's total matters; any other bounds give a holder a group of its own.
+#[derive(Debug, Clone)]
+struct Group {
+    mask: Option<u8>,
+    lower: BirdCounts,
+    upper: BirdCounts,
+    members: Vec<(Holder, i32)>,
+    capacity: i32,
+}
+
+/// A full assignment of the unknown cards, on top of the known ones.
+#[derive(Debug, Clone)]
+pub struct Deal {
+    pub hands: Vec<BirdCounts>,
+    pub discarded: BirdCounts,
+    pub deck: Vec<Bird>,
+}
+
+/// Samples uniformly among the deals of the unknown cards that respect every hand's
+/// and discard chunk's bounds per species, and the deck totals, at once.
+///
+/// Unbounded holders share a free pool with the deck. `tables[s][c]` counts the
+/// card-level deals of species `s` onwards that fill the groups' remaining capacities
+/// `c` exactly within their bounds, so species can be drawn one at a time without
+/// ever dead-ending. Within a species, `stages` keep the partial counts as each group
+/// takes its share, so the shares can be drawn one group at a time too. When few
+/// enough deals are consistent they are also listed outright, see `deals`.
+#[derive(Debug, Clone)]
+pub struct DealSampler {
+    known_hands: Vec<BirdCounts>,
+    known_discarded: BirdCounts,
+    known_deck: BirdCounts,
+    available: BirdCounts,
+    groups: Vec<Group>,
+    free_members: Vec<(Holder, i32)>,
+    strides: Vec<usize>,
+    tables: Vec<Vec<f64>>,
+    stages: Vec<Vec<(usize, Vec<f64>)>>,
+    shares: Vec<Vec<f64>>,
+    inverse_factorials: Vec<f64>,
+    /// Every consistent deal, with only the unknown cards in its deck, and its
+    /// probability. Empty when there are too many to enumerate.
+    exact: Vec<(Deal, f64)>,
+    /// Why the tracked bounds were loosened and what was kept, if they were.
+    relaxed: Option<String>,
+}
+
+impl DealSampler {
+    /// Builds a sampler for the state's hidden cards. Bounds that make the tables too
+    /// large, or that no deal meets because an inferred one was wrong, are loosened a
+    /// step at a time: the discard chunks' first, then one hand's at a time, those with
+    /// the most hidden cards first, down to the totals alone. Whether they were is told
+    /// by `relaxed`.
+    pub fn new(state: &CubirdsState) -> Result<DealSampler, String> {
+        state.check_conservation()?;
+
+        let known_hands: Vec<BirdCounts> = state.players.iter().map(|x| x.cards.known_cards).collect();
+        let mut known_discarded = BirdCounts::new();
+        for discard in &state.discard_pile {
+            known_discarded += discard.known_cards;
+        }
+
+        let hidden: Vec<i32> = state.players.iter().map(|x| x.cards.total_cards - x.cards.known_cards.total()).collect();
+        let mut hand_bounds = vec![Bounds::All; state.players.len()];
+        let mut discard_bounds = Bounds::All;
+        let mut sampler = None;
+        let mut errors = Vec::new();
+        loop {
+            let mut holders = Vec::new();
+            for (index, player) in state.players.iter().enumerate() {
+                holders.push(Hidden::from(Holder::Player(index), &player.cards, hand_bounds[index]));
+            }
+            for discard in &state.discard_pile {
+                holders.push(Hidden::from(Holder::Discard, discard, discard_bounds));
+            }
+            match DealSampler::from_holders(state.all_available_cards(), &holders, known_hands.clone(), known_discarded) {
+                Ok(mut built) => {
+                    if let Some(reason) = errors.first() {
+                        let mut kept: Vec<String> = (0..hand_bounds.len())
+                            .filter(|x| hand_bounds[*x] != Bounds::All)
+                            .map(|x| format!("{} for player {}'s hand", hand_bounds[x].describe(), x))
+                            .collect();
+                        if discard_bounds != Bounds::All {
+                            kept.push(format!("{} for discarded cards", discard_bounds.describe()));
+                        }
+                        built.relaxed = Some(format!("{} Sampling with {}.", reason, kept.join(", ")));
+                    }
+                    sampler = Some(built);
+                    break;
+                },
+                Err(error) => errors.push(error),
+            }
+
+            if discard_bounds != Bounds::Totals && !state.discard_pile.is_empty() {
+                discard_bounds = discard_bounds.looser();
+                continue;
+            }
+            let tightest = (0..hand_bounds.len())
+                .filter(|x| hand_bounds[*x] != Bounds::Totals && hidden[*x] > 0)
+                .min_by_key(|x| (hand_bounds[*x], -hidden[*x], *x));
+            match tightest {
+                Some(index) => hand_bounds[index] = hand_bounds[index].looser(),
+                None => break,
+            }
+        }
+
+        let mut sampler = sampler.ok_or_else(|| errors.pop().unwrap_or_default())?;
+        sampler.known_deck = state.draw_pile.known_cards;
+        return Ok(sampler);
+    }
+
+    fn from_holders(available: BirdCounts, holders: &[Hidden], known_hands: Vec<BirdCounts>, known_discarded: BirdCounts) -> Result<DealSampler, String> {
+        let mut groups: Vec<Group> = Vec::new();
+        let mut free_members = Vec::new();
+        for hidden in holders {
+            if hidden.count == 0 {
+                continue;
+            }
+            let mask = hidden.exclusion_mask();
+            if mask == Some(0) {
+                free_members.push((hidden.holder, hidden.count));
+            } else if let Some(group) = groups.iter_mut().find(|x| mask.is_some() && x.mask == mask) {
+                group.members.push((hidden.holder, hidden.count));
+                group.capacity += hidden.count;
+            } else {
+                let mut upper = hidden.upper;
+                if let Some(mask) = mask {
+                    for bird in Bird::iter().filter(|x| mask & (1 << x.index()) == 0) {
+                        upper[bird] = i32::MAX;
+                    }
+                }
+                groups.push(Group{
+                    mask,
+                    lower: hidden.lower,
+                    upper,
+                    members: vec![(hidden.holder, hidden.count)],
+                    capacity: hidden.count,
+                });
+            }
+        }
+
+        let constrained: i32 = groups.iter().map(|x| x.capacity).sum();
+        let free: i32 = free_members.iter().map(|x| x.1).sum();
+        if constrained + free > available.total() {
+            return Err(format!("{} unknown cards are tracked but only {} are unaccounted for.", constrained + free, available.total()));
+        }
+
+        let mut strides = Vec::new();
+        let mut size: usize = 1;
+        for group in &groups {
+            strides.push(size);
+            size = size.saturating_mul(group.capacity as usize + 1);
+        }
+        if size > MAX_TABLE_SIZE {
+            return Err(String::from("Too many constrained hidden hands to sample exactly."));
+        }
+        let mut stage_entries: usize = 0;
+        for bird in Bird::iter() {
+            let taking = groups.iter().filter(|x| x.lower[bird] > 0 || x.upper[bird] > 0).count();
+            stage_entries += size * (taking + 1) * (available[bird].max(0) as usize + 1);
+        }
+
+        let mut inverse_factorials = vec![1.0];
+        for n in 1..=available.total().max(0) as usize {
+            inverse_factorials.push(inverse_factorials[n - 1] / n as f64);
+        }
+
+        let mut sampler = DealSampler{
+            known_hands,
+            known_discarded,
+            known_deck: BirdCounts::new(),
+            available,
+            groups,
+            free_members,
+            strides,
+            tables: Vec::new(),
+            stages: Vec::new(),
+            shares: Vec::new(),
+            inverse_factorials,
+            exact: Vec::new(),
+            relaxed: None,
+        };
+        sampler.build_tables(size, stage_entries <= MAX_STAGE_ENTRIES);
+
+        if sampler.tables[0][sampler.full_index()] == 0.0 {
+            return Err(String::from("No deal of the unknown cards fits every bound."));
+        }
+        sampler.exact = sampler.enumerate(MAX_ENUMERATED_DEALS).unwrap_or_default();
+        return Ok(sampler);
+    }
+
+    fn full_index(&self) -> usize {
+        self.groups.iter().zip(&self.strides).map(|(group, stride)| group.capacity as usize * stride).sum()
+    }
+
+    fn capacity_at(&self, index: usize, group: usize) -> usize {
+        (index / self.strides[group]) % (self.groups[group].capacity as usize + 1)
+    }
+
+    /// How many cards of `bird` the group may take at most, and at least.
+    fn share_bounds(&self, group: usize, bird: Bird) -> (usize, usize) {
+        let group = &self.groups[group];
+        return (group.lower[bird].max(0) as usize, group.upper[bird].max(0) as usize);
+    }
+
+    /// Fills `tables` from the last species back. Within a species the groups take
+    /// their share one at a time, tracking how many of its cards `u` are used so far;
+    /// whatever is left goes to the free pool. With `keep_stages` the partial counts
+    /// are kept for sampling.
+    fn build_tables(&mut self, size: usize, keep_stages: bool) {
+        let mut tables = vec![Vec::new(); BIRDS + 1];
+        tables[BIRDS] = vec![0.0; size];
+        tables[BIRDS][0] = 1.0;
+        let mut stages = vec![Vec::new(); BIRDS];
+        let mut shares = vec![Vec::new(); BIRDS];
+
+        // Counts are only kept where they can matter: the groups can't hold more than
+        // the cards still to deal, and sampling never reaches less than the cards
+        // dealt so far leave.
+        let loads: Vec<usize> = (0..size).map(|index| (0..self.groups.len()).map(|x| self.capacity_at(index, x)).sum()).collect();
+        let full_load: usize = self.groups.iter().map(|x| x.capacity as usize).sum();
+        let bands: Vec<(usize, usize)> = (0..BIRDS)
+            .map(|species| {
+                let dealt: usize = Bird::iter().take(species).map(|x| self.available[x].max(0) as usize).sum();
+                let remaining: usize = Bird::iter().skip(species).map(|x| self.available[x].max(0) as usize).sum();
+                return (full_load.saturating_sub(dealt), remaining);
+            })
+            .collect();
+
+        for (species, bird) in Bird::iter().enumerate().rev() {
+            let cards = self.available[bird].max(0) as usize;
+            let width = cards + 1;
+            let (lowest, highest) = bands[species];
+            let relevant: Vec<usize> = (0..size).filter(|x| lowest.saturating_sub(cards) <= loads[*x] && loads[*x] <= highest).collect();
+            let mut used = vec![0.0; size * width];
+            for index in relevant.iter().copied() {
+                used[index * width] = tables[species + 1][index];
+            }
+
+            for group_idx in 0..self.groups.len() {
+                let (lower, upper) = self.share_bounds(group_idx, bird);
+                if lower == 0 && upper == 0 {
+                    continue;
+                }
+                let stride = self.strides[group_idx];
+                let mut next = vec![0.0; size * width];
+                for index in relevant.iter().copied() {
+                    let capacity = self.capacity_at(index, group_idx);
+                    let held: usize = (0..=group_idx).map(|x| self.capacity_at(index, x)).sum();
+                    for u in 0..width.min(held + 1) {
+                        let mut total = 0.0;
+                        for x in lower..=capacity.min(u).min(upper) {
+                            total += self.inverse_factorials[x] * used[(index - x * stride) * width + u - x];
+                        }
+                        next[index * width + u] = total;
+                    }
+                }
+                let before = std::mem::replace(&mut used, next);
+                if keep_stages {
+                    stages[species].push((group_idx, before));
+                }
+            }
+
+            tables[species] = vec![0.0; size];
+            for index in relevant.iter().copied().filter(|x| lowest <= loads[*x]) {
+                tables[species][index] = (0..width).map(|u| used[index * width + u] * self.inverse_factorials[cards - u]).sum();
+            }
+            if keep_stages {
+                shares[species] = used;
+            }
+        }
+
+        self.tables = tables;
+        if keep_stages {
+            self.stages = stages;
+            self.shares = shares;
+        }
+    }
+
+    /// Calls `visit` with every way the groups can take cards of `species` out of the
+    /// capacities at `index`, weighted by how many deals of the remaining species follow.
+    fn visit_shares(&self, species: usize, index: usize, group: usize, taken: &mut Vec<usize>, weight: f64, visit: &mut dyn FnMut(&[usize], f64)) {
+        let bird = Bird::iter().nth(species).unwrap();
+        let cards = self.available[bird].max(0) as usize;
+        let used: usize = taken.iter().sum();
+        if group == self.groups.len() {
+            let remaining = index - taken.iter().zip(&self.strides).map(|(x, stride)| x * stride).sum::<usize>();
+            visit(taken, weight * self.inverse_factorials[cards - used] * self.tables[species + 1][remaining]);
+            return;
+        }
+
+        let (lower, upper) = self.share_bounds(group, bird);
+        for x in lower..=self.capacity_at(index, group).min(cards - used).min(upper) {
+            taken.push(x);
+            self.visit_shares(species, index, group + 1, taken, weight * self.inverse_factorials[x], visit);
+            taken.pop();
+        }
+    }
+
+    /// Draws how many cards of `species` each group takes, going through every
+    /// combination of shares.
+    fn enumerate_shares<R: Rng>(&self, rng: &mut R, species: usize, index: usize) -> Vec<usize> {
+        let mut total = 0.0;
+        self.visit_shares(species, index, 0, &mut Vec::new(), 1.0, &mut |_, weight| total += weight);
+
+        let target = rng.gen::<f64>() * total;
+        let mut visited = 0.0;
+        let mut chosen: Option<Vec<usize>> = None;
+        self.visit_shares(species, index, 0, &mut Vec::new(), 1.0, &mut |taken, weight| {
+            if weight > 0.0 && (chosen.is_none() || visited <= target) {
+                chosen = Some(taken.to_vec());
+            }
+            visited += weight;
+        });
+        return chosen.unwrap();
+    }
+
+    /// Draws how many cards of `species` each group takes from the kept stages: first
+    /// how many the groups take between them, then each group's share, last group first.
+    fn staged_shares<R: Rng>(&self, rng: &mut R, species: usize, mut index: usize) -> Vec<usize> {
+        let bird = Bird::iter().nth(species).unwrap();
+        let cards = self.available[bird].max(0) as usize;
+        let width = cards + 1;
+        let options: Vec<(usize, f64)> = (0..width)
+            .map(|u| (u, self.shares[species][index * width + u] * self.inverse_factorials[cards - u]))
+            .collect();
+        let mut u = DealSampler::choose(rng, &options);
+
+        let mut taken = vec![0; self.groups.len()];
+        for (group, before) in self.stages[species].iter().rev() {
+            let (lower, upper) = self.share_bounds(*group, bird);
+            let stride = self.strides[*group];
+            let options: Vec<(usize, f64)> = (lower..=self.capacity_at(index, *group).min(u).min(upper))
+                .map(|x| (x, self.inverse_factorials[x] * before[(index - x * stride) * width + u - x]))
+                .collect();
+            taken[*group] = DealSampler::choose(rng, &options);
+            index -= taken[*group] * stride;
+            u -= taken[*group];
+        }
+        return taken;
+    }
+
+    /// Every distinct deal with its probability, or None if there may be more than
+    /// `limit`. Their decks hold only the unknown cards.
+    fn enumerate(&self, limit: usize) -> Option<Vec<(Deal, f64)>> {
+        let mut pools: Vec<Pool> = self.groups.iter()
+            .map(|x| x.members.iter().map(|(holder, count)| (Some(*holder), *count)).collect())
+            .collect();
+        let mut free: Pool = self.free_members.iter().map(|(holder, count)| (Some(*holder), *count)).collect();
+        let held: i32 = self.groups.iter().map(|x| x.capacity).sum::<i32>() + free.iter().map(|x| x.1).sum::<i32>();
+        free.push((None, self.available.total() - held));
+        pools.push(free);
+
+        // Every holder but the deck holds some choice of its count of cards out of the
+        // species its pool may take, so the deals are at most the product of how many
+        // choices there are.
+        let mut most_deals: usize = 1;
+        for (pool_idx, pool) in pools.iter().enumerate() {
+            let species = Bird::iter()
+                .filter(|x| self.available[*x] > 0 && self.groups.get(pool_idx).is_none_or(|group| group.upper[*x] > 0))
+                .count();
+            for (_, count) in pool.iter().filter(|x| x.0.is_some()) {
+                let choices = (0..species.saturating_sub(1)).fold(1usize, |x, i| x.saturating_mul(*count as usize + i + 1) / (i + 1));
+                most_deals = most_deals.saturating_mul(choices);
+            }
+        }
+        if most_deals > limit {
+            return None;
+        }
+
+        let mut weights: HashMap<DealKey, f64> = HashMap::new();
+        let mut compositions = vec![BirdCounts::new(); pools.len()];
+        self.visit_compositions(0, self.full_index(), &mut compositions, &mut |compositions| {
+            let splits: Vec<Vec<(Vec<BirdCounts>, f64)>> = pools.iter()
+                .zip(compositions)
+                .map(|(pool, cards)| self.splits(*cards, &pool.iter().map(|x| x.1).collect::<Vec<i32>>()))
+                .collect();
+            let empty = (self.known_hands.clone(), self.known_discarded, BirdCounts::new());
+            DealSampler::visit_products(&pools, &splits, &empty, 1.0, &mut |deal, weight| *weights.entry(deal).or_default() += weight);
+        });
+
+        let total: f64 = weights.values().sum();
+        return Some(weights.into_iter()
+            .map(|((hands, discarded, deck), weight)| (Deal{hands, discarded, deck: deck.to_vec()}, weight / total))
+            .collect());
+    }
+
+    /// Calls `visit` with every way the tables allow of filling the groups, followed
+    /// by the free pool.
+    fn visit_compositions(&self, species: usize, index: usize, compositions: &mut [BirdCounts], visit: &mut dyn FnMut(&[BirdCounts])) {
+        if species == BIRDS {
+            visit(compositions);
+            return;
+        }
+        let bird = Bird::iter().nth(species).unwrap();
+        let cards = self.available[bird].max(0) as usize;
+        let mut options = Vec::new();
+        self.visit_shares(species, index, 0, &mut Vec::new(), 1.0, &mut |taken, weight| {
+            if weight > 0.0 {
+                options.push(taken.to_vec());
+            }
+        });
+
+        for taken in options {
+            let mut next = index;
+            for (group, count) in taken.iter().enumerate() {
+                compositions[group][bird] = *count as i32;
+                next -= count * self.strides[group];
+            }
+            compositions[self.groups.len()][bird] = (cards - taken.iter().sum::<usize>()) as i32;
+            self.visit_compositions(species + 1, next, compositions, visit);
+        }
+    }
+
+    /// Every way of splitting `cards` into shares of the given sizes, weighted by how
+    /// many card-level deals give it.
+    fn splits(&self, cards: BirdCounts, sizes: &[i32]) -> Vec<(Vec<BirdCounts>, f64)> {
+        let Some((size, rest)) = sizes.split_first() else {
+            return vec![(Vec::new(), 1.0)];
+        };
+        let mut shares = Vec::new();
+        if rest.is_empty() {
+            shares.push(cards);
+        } else {
+            DealSampler::subsets(cards, *size, 0, &mut BirdCounts::new(), &mut shares);
+        }
+
+        let mut splits = Vec::new();
+        for share in shares {
+            let weight: f64 = Bird::iter().map(|x| self.inverse_factorials[share[x] as usize]).product();
+            for (mut split, rest_weight) in self.splits(cards - share, rest) {
+                split.insert(0, share);
+                splits.push((split, weight * rest_weight));
+            }
+        }
+        return splits;
+    }
+
+    /// Collects every choice of `size` cards out of `cards`, species `species` onwards.
+    fn subsets(cards: BirdCounts, size: i32, species: usize, share: &mut BirdCounts, shares: &mut Vec<BirdCounts>) {
+        if species == BIRDS {
+            if size == 0 {
+                shares.push(*share);
+            }
+            return;
+        }
+        let bird = Bird::iter().nth(species).unwrap();
+        for count in 0..=cards[bird].min(size) {
+            share[bird] = count;
+            DealSampler::subsets(cards, size - count, species + 1, share, shares);
+        }
+        share[bird] = 0;
+    }
+
+    /// Calls `visit` with every combination of one split per pool, as the deal it
+    /// makes and its weight.
+    fn visit_products(pools: &[Pool], splits: &[Vec<(Vec<BirdCounts>, f64)>], deal: &DealKey, weight: f64, visit: &mut dyn FnMut(DealKey, f64)) {
+        let Some((options, rest)) = splits.split_first() else {
+            visit(deal.clone(), weight);
+            return;
+        };
+        for (shares, share_weight) in options {
+            let mut next = deal.clone();
+            for ((holder, _), share) in pools[0].iter().zip(shares) {
+                match holder {
+                    Some(Holder::Player(index)) => next.0[*index] += *share,
+                    Some(Holder::Discard) => next.1 += *share,
+                    None => next.2 += *share,
+                }
+            }
+            DealSampler::visit_products(&pools[1..], rest, &next, weight * share_weight, visit);
+        }
+    }
+
+    /// Why some tracked bounds aren't respected, and which are, if any were loosened.
+    pub fn relaxed(&self) -> Option<&str> {
+        return self.relaxed.as_deref();
+    }
+
+    /// How many deals are consistent, if few enough to be enumerated.
+    pub fn exact_deals(&self) -> Option<usize> {
+        return (!self.exact.is_empty()).then_some(self.exact.len());
+    }
+
+    /// The enumerated deals, known deck cards included, with their probabilities.
+    pub fn deals(&self) -> Option<Vec<(Deal, f64)>> {
+        if self.exact.is_empty() {
+            return None;
+        }
+        return Some(self.exact.iter()
+            .map(|(deal, probability)| {
+                let mut deal = deal.clone();
+                deal.deck.extend(self.known_deck.to_vec());
+                return (deal, *probability);
+            })
+            .collect());
+    }
+
+    /// Picks an option with probability proportional to its weight.
+    fn choose<R: Rng>(rng: &mut R, options: &[(usize, f64)]) -> usize {
+        let total: f64 = options.iter().map(|x| x.1).sum();
+        let mut target = rng.gen::<f64>() * total;
+        let mut chosen = 0;
+        for (option, weight) in options {
+            if *weight > 0.0 {
+                chosen = *option;
+                if target < *weight {
+                    break;
+                }
+                target -= weight;
+            }
+        }
+        return chosen;
+    }
+
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Deal {
+        let mut group_cards = vec![Vec::new(); self.groups.len()];
+        let mut free_cards = Vec::new();
+        let mut index = self.full_index();
+
+        for (species, bird) in Bird::iter().enumerate() {
+            let taken = if self.shares.is_empty() {
+                self.enumerate_shares(rng, species, index)
+            } else {
+                self.staged_shares(rng, species, index)
+            };
+            for (group, count) in taken.iter().enumerate() {
+                group_cards[group].extend(std::iter::repeat_n(bird, *count));
+                index -= count * self.strides[group];
+            }
+            let free = self.available[bird].max(0) as usize - taken.iter().sum::<usize>();
+            free_cards.extend(std::iter::repeat_n(bird, free));
+        }
+
+        let mut deal = Deal{
+            hands: self.known_hands.clone(),
+            discarded: self.known_discarded,
+            deck: Vec::new(),
+        };
+        for (group, mut cards) in self.groups.iter().zip(group_cards) {
+            cards.shuffle(rng);
+            deal.hand_out(&mut cards, &group.members);
+        }
+        free_cards.shuffle(rng);
+        deal.hand_out(&mut free_cards, &self.free_members);
+        free_cards.extend(self.known_deck.to_vec());
+        free_cards.shuffle(rng);
+        deal.deck = free_cards;
+        return deal;
+    }
+}
+
+impl Deal {
+    /// Gives each member its share from the end of `cards`.
+    fn hand_out(&mut self, cards: &mut Vec<Bird>, members: &[(Holder, i32)]) {
+        for (holder, hidden) in members {
+            let share = BirdCounts::from_birds(&cards.split_off(cards.len() - *hidden as usize));
+            match holder {
+                Holder::Player(index) => self.hands[*index] += share,
+                Holder::Discard => self.discarded += share,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::sink;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::state::EXAMPLE_SETUP;
+    use super::*;
+
+    fn excluding(holder: Holder, count: i32, birds: &[Bird]) -> Hidden {
+        let mut upper = BirdCounts::uniform(count);
+        for bird in birds {
+            upper[*bird] = 0;
+        }
+        return Hidden{holder, count, lower: BirdCounts::new(), upper};
+    }
+
+    fn bounded(holder: Holder, count: i32, bird: Bird, lower: i32, upper: i32) -> Hidden {
+        let mut hidden = excluding(holder, count, &[]);
+        hidden.lower[bird] = lower;
+        hidden.upper[bird] = upper;
+        return hidden;
+    }
+
+    fn sampler(available: &[Bird], holders: &[Hidden]) -> Result<DealSampler, String> {
+        let players = holders.iter().filter(|x| matches!(x.holder, Holder::Player(_))).count();
+        return DealSampler::from_holders(BirdCounts::from_birds(available), holders, vec![BirdCounts::new(); players], BirdCounts::new());
+    }
+
+    /// Visits every ordering of `cards`, treating equal birds as different cards.
+    fn permutations(cards: &mut Vec<Bird>, start: usize, visit: &mut dyn FnMut(&[Bird])) {
+        if start == cards.len() {
+            visit(cards);
+            return;
+        }
+        for idx in start..cards.len() {
+            cards.swap(start, idx);
+            permutations(cards, start + 1, visit);
+            cards.swap(start, idx);
+        }
+    }
+
+    /// Compares sampled deals against every card-level deal within the bounds, with
+    /// players listed before a single discard chunk.
+    fn assert_uniform(available: &str, holders: &[Hidden]) {
+        let mut available = Bird::from_string(available).unwrap();
+        let mut expected: HashMap<Vec<BirdCounts>, f64> = HashMap::new();
+        permutations(&mut available, 0, &mut |cards| {
+            let mut shares = Vec::new();
+            let mut start = 0;
+            for hidden in holders {
+                let share = BirdCounts::from_birds(&cards[start..start + hidden.count as usize]);
+                start += hidden.count as usize;
+                if Bird::iter().any(|x| share[x] < hidden.lower[x] || share[x] > hidden.upper[x]) {
+                    return;
+                }
+                shares.push(share);
+            }
+            *expected.entry(shares).or_default() += 1.0;
+        });
+        let consistent: f64 = expected.values().sum();
+
+        let has_discard = holders.iter().any(|x| x.holder == Holder::Discard);
+        let shares_of = |deal: &Deal| {
+            let mut shares = deal.hands.clone();
+            if has_discard {
+                shares.push(deal.discarded);
+            }
+            return shares;
+        };
+
+        // The enumerated deals carry their exact probabilities.
+        let staged = sampler(&available, holders).unwrap();
+        assert_eq!(staged.exact_deals(), Some(expected.len()));
+        for (deal, probability) in &staged.exact {
+            assert!((probability - expected[&shares_of(deal)] / consistent).abs() < 1e-9);
+        }
+
+        // Both ways of drawing the shares, from the kept stages and by going through
+        // every combination.
+        let mut enumerating = staged.clone();
+        enumerating.stages.clear();
+        enumerating.shares.clear();
+        for sampler in [staged, enumerating] {
+            let mut rng = StdRng::seed_from_u64(0);
+            let samples = 40000;
+            let mut observed: HashMap<Vec<BirdCounts>, f64> = HashMap::new();
+            for _ in 0..samples {
+                let deal = sampler.sample(&mut rng);
+                assert_eq!(deal.deck.len() as i32, available.len() as i32 - holders.iter().map(|x| x.count).sum::<i32>());
+                *observed.entry(shares_of(&deal)).or_default() += 1.0;
+            }
+
+            assert_eq!(observed.len(), expected.len());
+            for (deal, count) in &expected {
+                let frequency = observed.get(deal).copied().unwrap_or(0.0) / samples as f64;
+                assert!((frequency - count / consistent).abs() < 0.01, "{:?}: {} vs {}", deal, frequency, count / consistent);
+            }
+        }
+    }
+
+    #[test]
+    fn samples_uniformly_over_consistent_deals() {
+        assert_uniform("PPWWRO", &[
+            excluding(Holder::Player(0), 2, &[Bird::PARROT]),
+            excluding(Holder::Player(1), 1, &[Bird::WARBLER, Bird::ROBIN]),
+            excluding(Holder::Discard, 1, &[]),
+        ]);
+    }
+
+    #[test]
+    fn samples_uniformly_within_ranges() {
+        assert_uniform("PPPWWRO", &[
+            bounded(Holder::Player(0), 3, Bird::PARROT, 1, 2),
+            bounded(Holder::Player(1), 2, Bird::WARBLER, 0, 1),
+            bounded(Holder::Discard, 1, Bird::OWL, 0, 0),
+        ]);
+    }
+
+    #[test]
+    fn never_dead_ends_where_seat_order_would() {
+        let holders = [
+            excluding(Holder::Player(0), 1, &[]),
+            excluding(Holder::Player(1), 1, &[Bird::PARROT]),
+        ];
+        let sampler = sampler(&[Bird::PARROT, Bird::WARBLER], &holders).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let deal = sampler.sample(&mut rng);
+            assert_eq!(deal.hands[0], BirdCounts::from_birds(&[Bird::PARROT]));
+            assert_eq!(deal.hands[1], BirdCounts::from_birds(&[Bird::WARBLER]));
+        }
+    }
+
+    #[test]
+    fn reports_unsatisfiable_constraints() {
+        assert!(sampler(&[Bird::PARROT, Bird::PARROT], &[excluding(Holder::Player(0), 1, &[Bird::PARROT])]).is_err());
+        assert!(sampler(&[Bird::PARROT, Bird::WARBLER], &[excluding(Holder::Player(0), 1, &[Bird::PARROT]), excluding(Holder::Player(1), 1, &[Bird::PARROT])]).is_err());
+        assert!(sampler(&[Bird::WARBLER], &[excluding(Holder::Player(0), 1, &[]), excluding(Holder::Discard, 1, &[])]).is_err());
+        assert!(sampler(&[Bird::WARBLER, Bird::PARROT], &[bounded(Holder::Player(0), 1, Bird::PARROT, 2, 2)]).is_err());
+    }
+
+    #[test]
+    fn tells_when_bounds_are_loosened() {
+        let mut state = CubirdsState::read_setup(&mut EXAMPLE_SETUP.as_bytes(), &mut sink()).unwrap();
+        assert_eq!(DealSampler::new(&state).unwrap().relaxed(), None);
+
+        // Too few owls are left for two hands of nothing else.
+        for bird in Bird::iter().filter(|x| *x != Bird::OWL) {
+            state.players[1].cards.max_cards[bird] = 0;
+            state.players[2].cards.max_cards[bird] = 0;
+        }
+        let sampler = DealSampler::new(&state).unwrap();
+        let relaxed = sampler.relaxed().unwrap();
+        assert!(relaxed.starts_with("No deal of the unknown cards fits every bound."), "{}", relaxed);
+        assert!(relaxed.contains("only the totals for player 1's hand"), "{}", relaxed);
+        assert!(!relaxed.contains("player 0") && !relaxed.contains("discarded"), "{}", relaxed);
+    }
+
+    #[test]
+    fn keeps_ranges_for_as_many_hands_as_fit() {
+        let setup = "5\n1\n0\nPPRROFWM\nP\nO\nD\nR\nW\nRRW\nPPO\nFMM\nMWW\n";
+        let mut state = CubirdsState::read_setup(&mut setup.as_bytes(), &mut sink()).unwrap();
+        for player in 1..5 {
+            let cards = &mut state.players[player].cards;
+            if player < 4 {
+                cards.total_cards += 12;
+                state.draw_pile.total_cards -= 12;
+            }
+            cards.max_cards = BirdCounts::uniform(cards.total_cards);
+            cards.max_cards[Bird::PARROT] = 3;
+        }
+
+        let sampler = DealSampler::new(&state).unwrap();
+        let relaxed = sampler.relaxed().unwrap();
+        assert_eq!(relaxed, "Too many constrained hidden hands to sample exactly. Sampling with only the species ruled out for player 1's hand.");
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let deal = sampler.sample(&mut rng);
+            assert!((2..5).all(|x| deal.hands[x][Bird::PARROT] <= 3));
+        }
+    }
+}
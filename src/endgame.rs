@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use crate::bird::Bird;
+use crate::bird_counts::BirdCounts;
+use crate::deal_sampler::DealSampler;
+use crate::evaluation::SearchPosition;
+use crate::line::Line;
+use crate::materialized_state::{FlockMove, LineMove, MaterializedCubirdsState, TurnPhase};
+use crate::search_tree::PlyMove;
+use crate::transposition::{hash_of, position_hash, TranspositionTable};
+use crate::LINES;
+
+/// Positions looked at before a solve is given up as too large.
+const MAX_SOLVER_NODES: usize = 1 << 18;
+/// Turns looked ahead before a solve is given up, which also keeps it out of cycles.
+const MAX_SOLVER_TURNS: usize = 8;
+/// Positions looked at between checks on whether the solve was cancelled.
+const CANCEL_CHECK_NODES: usize = 1 << 10;
+/// Solved positions kept, across solves.
+const SOLVER_TABLE_SIZE: usize = 1 << 16;
+
+/// How far into a turn a solver position is, including the card draws a turn waits on.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+enum Stage {
+    Play,
+    /// Drawing birds onto a line until it has two species again.
+    Refill{line: usize, left: bool},
+    /// Drawing this many cards into the hand.
+    Draw(i32),
+    Flock,
+}
+
+/// A position with every hand known. The deck is shuffled uniformly, so only its
+/// composition matters.
+#[derive(Debug, Clone)]
+struct Position {
+    board: [Line; LINES],
+    hands: Vec<BirdCounts>,
+    collections: Vec<BirdCounts>,
+    deck: BirdCounts,
+    discard: BirdCounts,
+    turn: usize,
+    stage: Stage,
+}
+
+impl Position {
+    fn from(state: &MaterializedCubirdsState, stage: Stage) -> Position {
+        return Position{
+            board: state.board.clone(),
+            hands: state.players.iter().map(|x| x.cards).collect(),
+            collections: state.players.iter().map(|x| x.collection).collect(),
+            deck: BirdCounts::from_birds(&state.draw_pile),
+            discard: state.discard_pile,
+            turn: state.turn,
+            stage,
+        };
+    }
+
+    /// Like `MaterializedCubirdsState::canonical_hash`, with the stage. A line being
+    /// refilled is named by its birds rather than its place, which the hash ignores;
+    /// it holds a single species, so which end it is refilled from doesn't matter either.
+    fn canonical_hash(&self) -> u64 {
+        let stage = match self.stage {
+            Stage::Play => (0, 0),
+            Stage::Refill{line, ..} => (1, self.board[line].canonical_hash()),
+            Stage::Draw(cards) => (2, cards as u64),
+            Stage::Flock => (3, 0),
+        };
+        return hash_of((position_hash(&self.board, &self.hands, &self.collections, self.deck, self.discard, self.turn), stage));
+    }
+}
+
+/// Exact win probabilities by expectimax over the rest of a game with every hand known.
+/// Each player picks what gives them the best chance to win, card draws are averaged
+/// over the deck, and values are kept per canonical position, so transpositions are
+/// solved once and later solves reuse them. Solves that would need a redeal, that
+/// grow past the node or turn limits, or that are cancelled, give up.
+pub struct EndgameSolver {
+    table: TranspositionTable<Vec<f64>>,
+    nodes: usize,
+    /// Checked every few positions; the solve gives up once it returns true.
+    cancelled: Box<dyn Fn() -> bool + Send>,
+}
+
+impl EndgameSolver {
+    pub fn new() -> EndgameSolver {
+        return EndgameSolver{
+            table: TranspositionTable::new(SOLVER_TABLE_SIZE),
+            nodes: 0,
+            cancelled: Box::new(|| false),
+        };
+    }
+
+    /// Every player's chance to win after each of the current player's options at
+    /// `phase`, keyed like the search tree's children.
+    pub fn solve(&mut self, state: &MaterializedCubirdsState, phase: &TurnPhase) -> Option<HashMap<PlyMove, Vec<f64>>> {
+        self.nodes = 0;
+        return self.solve_moves(state, phase);
+    }
+
+    /// Every player's exact chance to win after each of the current player's options,
+    /// when the sampler leaves a single deal, so every hand is known. Gives up once
+    /// `cancelled` returns true.
+    pub fn solve_deal(&mut self, position: &SearchPosition, sampler: &DealSampler, cancelled: impl Fn() -> bool + Send + 'static) -> Option<HashMap<PlyMove, Vec<f64>>> {
+        let mut deals = sampler.deals()?;
+        if deals.len() != 1 {
+            return None;
+        }
+        let (deal, _) = deals.pop().unwrap();
+        let state = MaterializedCubirdsState::from_deal(&position.state, deal, StdRng::seed_from_u64(0));
+        self.cancelled = Box::new(cancelled);
+        let solved = self.solve(&state, &position.phase);
+        self.cancelled = Box::new(|| false);
+        return solved;
+    }
+
+    fn solve_moves(&mut self, state: &MaterializedCubirdsState, phase: &TurnPhase) -> Option<HashMap<PlyMove, Vec<f64>>> {
+        let mut moves = HashMap::new();
+        match phase {
+            TurnPhase::Play => {
+                for (line_move, value) in self.line_moves(&Position::from(state, Stage::Play), 0, true)? {
+                    moves.insert(PlyMove::Line(line_move), value);
+                }
+            },
+            TurnPhase::Draw(line_move) => {
+                let position = Position::from(state, Stage::Flock);
+                for draw in [false, true] {
+                    let stage = if draw { Stage::Draw(2) } else { Stage::Flock };
+                    let value = self.value(&Position{stage, ..position.clone()}, 0)?;
+                    moves.insert(PlyMove::Line(LineMove{draw, ..line_move.clone()}), value);
+                }
+            },
+            TurnPhase::Flock => {
+                for (flock_move, value) in self.flock_moves(&Position::from(state, Stage::Flock), 0, true)? {
+                    moves.insert(PlyMove::Flock(flock_move), value);
+                }
+            },
+        }
+        return Some(moves);
+    }
+
+    fn value(&mut self, position: &Position, turns: usize) -> Option<Vec<f64>> {
+        let hash = position.canonical_hash();
+        if let Some(value) = self.table.get(hash) {
+            return Some(value.clone());
+        }
+        self.nodes += 1;
+        if self.nodes > MAX_SOLVER_NODES || turns > MAX_SOLVER_TURNS {
+            return None;
+        }
+        if self.nodes % CANCEL_CHECK_NODES == 1 && (self.cancelled)() {
+            return None;
+        }
+
+        let value = match position.stage {
+            Stage::Play => {
+                let moves = self.line_moves(position, turns, false)?;
+                EndgameSolver::best(moves.into_iter().map(|x| x.1), position.turn)?
+            },
+            Stage::Refill{line, left} => self.chance(position, turns, |next, bird| {
+                next.board[line].extend(&[bird], left);
+                if !next.board[line].needs_refill() {
+                    next.stage = Stage::Flock;
+                }
+            })?,
+            Stage::Draw(cards) => self.chance(position, turns, |next, bird| {
+                next.hands[next.turn][bird] += 1;
+                next.stage = if cards > 1 { Stage::Draw(cards - 1) } else { Stage::Flock };
+            })?,
+            Stage::Flock => {
+                let moves = self.flock_moves(position, turns, false)?;
+                EndgameSolver::best(moves.into_iter().map(|x| x.1), position.turn)?
+            },
+        };
+        self.table.insert(hash, value.clone());
+        return Some(value);
+    }
+
+    /// The value of every way to place birds, with the mover's best refill direction
+    /// after a sandwich. Unless `exhaustive`, a play that ensures the mover's win ends
+    /// the search early.
+    fn line_moves(&mut self, position: &Position, turns: usize, exhaustive: bool) -> Option<Vec<(LineMove, Vec<f64>)>> {
+        let mover = position.turn;
+        let mut moves = Vec::new();
+        for (bird, count) in position.hands[mover].iter() {
+            for line in 0..LINES {
+                for left in [true, false] {
+                    let mut next = position.clone();
+                    next.hands[mover].remove(bird);
+                    let line_move = LineMove{line, bird, left, draw: false};
+                    if let Some(sandwiched) = next.board[line].play(bird, count, left) {
+                        next.hands[mover] += sandwiched;
+                        let value = if next.board[line].needs_refill() {
+                            let mut refills = Vec::new();
+                            for refill_left in [true, false] {
+                                refills.push(self.value(&Position{stage: Stage::Refill{line, left: refill_left}, ..next.clone()}, turns)?);
+                            }
+                            EndgameSolver::best(refills.into_iter(), mover)?
+                        } else {
+                            self.value(&Position{stage: Stage::Flock, ..next}, turns)?
+                        };
+                        moves.push((line_move, value));
+                    } else {
+                        let value = self.value(&Position{stage: Stage::Flock, ..next.clone()}, turns)?;
+                        moves.push((line_move.clone(), value));
+                        let value = self.value(&Position{stage: Stage::Draw(2), ..next}, turns)?;
+                        moves.push((LineMove{draw: true, ..line_move}, value));
+                    }
+                    if !exhaustive && moves.iter().any(|x| x.1[mover] >= 1.0) {
+                        return Some(moves);
+                    }
+                }
+            }
+        }
+        return Some(moves);
+    }
+
+    /// The value of flying each flock home, then of keeping the hand, ending the turn
+    /// either way. Unless `exhaustive`, a flock that wins ends the search early.
+    fn flock_moves(&mut self, position: &Position, turns: usize, exhaustive: bool) -> Option<Vec<(FlockMove, Vec<f64>)>> {
+        let mover = position.turn;
+        let mut options: Vec<Option<Bird>> = position.hands[mover].iter()
+            .filter(|(bird, count)| *count >= bird.information().small)
+            .map(|x| Some(x.0))
+            .collect();
+        options.push(None);
+
+        let mut moves = Vec::new();
+        for bird in options {
+            let mut next = position.clone();
+            if let Some(bird) = bird {
+                let count = next.hands[mover].remove(bird);
+                let flock = if count >= bird.information().large { 2 } else { 1 };
+                next.collections[mover][bird] += flock;
+                next.discard[bird] += count - flock;
+            }
+            let value = self.end_turn(next, turns)?;
+            let won = value[mover] >= 1.0;
+            moves.push((FlockMove{bird}, value));
+            if won && !exhaustive {
+                break;
+            }
+        }
+        return Some(moves);
+    }
+
+    fn end_turn(&mut self, mut position: Position, turns: usize) -> Option<Vec<f64>> {
+        let players = position.hands.len();
+        if let Some(winner) = position.collections.iter().position(MaterializedCubirdsState::collection_wins) {
+            let mut value = vec![0.0; players];
+            value[winner] = 1.0;
+            return Some(value);
+        }
+        if position.hands[position.turn].is_empty() {
+            return None;
+        }
+        position.turn = (position.turn + 1) % players;
+        position.stage = Stage::Play;
+        return self.value(&position, turns + 1);
+    }
+
+    /// Averages over the card drawn next, reshuffling the discards once the deck runs
+    /// out. With no cards left at all the game can't go on, and no one wins it.
+    fn chance(&mut self, position: &Position, turns: usize, apply: impl Fn(&mut Position, Bird)) -> Option<Vec<f64>> {
+        let mut drawing = position.clone();
+        if drawing.deck.is_empty() {
+            drawing.deck = std::mem::take(&mut drawing.discard);
+        }
+        let mut value = vec![0.0; position.hands.len()];
+        let cards = drawing.deck.total() as f64;
+        for (bird, count) in drawing.deck.iter() {
+            let mut next = drawing.clone();
+            next.deck[bird] -= 1;
+            apply(&mut next, bird);
+            let outcome = self.value(&next, turns)?;
+            for (total, chance) in value.iter_mut().zip(outcome) {
+                *total += count as f64 / cards * chance;
+            }
+        }
+        return Some(value);
+    }
+
+    /// The option best for `mover`, the first of any that tie.
+    fn best(values: impl Iterator<Item = Vec<f64>>, mover: usize) -> Option<Vec<f64>> {
+        let mut best: Option<Vec<f64>> = None;
+        for value in values {
+            if best.as_ref().is_none_or(|x| value[mover] > x[mover]) {
+                best = Some(value);
+            }
+        }
+        return best;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::player::MaterializedPlayer;
+    use super::*;
+
+    fn birds(string: &str) -> BirdCounts {
+        return BirdCounts::from_birds(&Bird::from_string(string).unwrap());
+    }
+
+    /// Player 0 holds two flamingos and a toucan next to three owls and a flamingo, so a
+    /// third flamingo wins. Player 1 wins on their turn whatever player 0 does.
+    fn close_game() -> MaterializedCubirdsState {
+        let player = |collection: &str, cards: &str| MaterializedPlayer{collection: birds(collection), cards: birds(cards)};
+        return MaterializedCubirdsState{
+            board: ["PWR", "WRM", "RMD", "MDP"].map(|x| Line::from_birds(&Bird::from_string(x).unwrap())),
+            players: vec![player("OOOF", "FFTP"), player("FFFOOTT", "OOOTTT")],
+            player_idx: 0,
+            draw_pile: Bird::from_string("FPPP").unwrap(),
+            discard_pile: BirdCounts::new(),
+            turn: 0,
+            rng: StdRng::seed_from_u64(0),
+            log: None,
+        };
+    }
+
+    #[test]
+    fn wins_with_the_flock_that_completes_a_collection() {
+        let mut game = close_game();
+        game.players[0].cards = birds("FFFP");
+        let moves = EndgameSolver::new().solve(&game, &TurnPhase::Flock).unwrap();
+        assert_eq!(moves[&PlyMove::Flock(FlockMove{bird: Some(Bird::FLAMINGO)})], vec![1.0, 0.0]);
+        assert_eq!(moves[&PlyMove::Flock(FlockMove{bird: None})], vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn weighs_draws_by_the_deck() {
+        let moves = EndgameSolver::new().solve(&close_game(), &TurnPhase::Play).unwrap();
+        for (ply, chances) in &moves {
+            assert!((chances.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+            let PlyMove::Line(line_move) = ply else {
+                panic!("{:?} isn't a play", ply);
+            };
+            // One of the two cards drawn is the last flamingo half of the time, which
+            // only helps if the flamingos weren't the birds played.
+            let expected = if line_move.draw && line_move.bird != Bird::FLAMINGO { 0.5 } else { 0.0 };
+            assert!((chances[0] - expected).abs() < 1e-9, "{:?}: {:?}", line_move, chances);
+        }
+    }
+
+    #[test]
+    fn reuses_solved_transpositions() {
+        let mut solver = EndgameSolver::new();
+        let moves = solver.solve(&close_game(), &TurnPhase::Play).unwrap();
+        assert!(solver.nodes > 0);
+        assert_eq!(Position::from(&close_game(), Stage::Play).canonical_hash(), hash_of((close_game().canonical_hash(), (0, 0u64))));
+
+        // The same position with its lines swapped and reversed is looked up whole.
+        let mut moved = close_game();
+        moved.board.swap(0, 3);
+        moved.board[1] = Line::from_birds(&Bird::from_string("MRW").unwrap());
+        let moved_moves = solver.solve(&moved, &TurnPhase::Play).unwrap();
+        assert_eq!(solver.nodes, 0);
+        let best = |moves: &HashMap<PlyMove, Vec<f64>>| moves.values().map(|x| x[0]).fold(0.0, f64::max);
+        assert_eq!(best(&moved_moves), best(&moves));
+    }
+
+    #[test]
+    fn gives_up_when_cancelled() {
+        let mut solver = EndgameSolver::new();
+        solver.cancelled = Box::new(|| true);
+        assert!(solver.solve(&close_game(), &TurnPhase::Play).is_none());
+        solver.cancelled = Box::new(|| false);
+        assert!(solver.solve(&close_game(), &TurnPhase::Play).is_some());
+    }
+
+    #[test]
+    fn gives_up_on_whole_games() {
+        let game = MaterializedCubirdsState::deal(3, StdRng::seed_from_u64(0)).unwrap();
+        assert!(EndgameSolver::new().solve(&game, &TurnPhase::Play).is_none());
+    }
+}
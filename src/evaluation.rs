@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, SeedableRng};
+use crate::deal_sampler::{Deal, DealSampler};
+use crate::endgame::EndgameSolver;
+use crate::materialized_state::{MaterializedCubirdsState, TurnPhase};
+use crate::search_tree::{PlyMove, SearchNode};
+use crate::state::CubirdsState;
+
+const BATCH_ROLLOUTS: usize = 64;
+/// Rollouts every arm gets before any is favoured.
+const MIN_ARM_ROLLOUTS: usize = 16;
+/// Weight of the exploration term in an arm's upper confidence bound.
+const EXPLORATION: f64 = 1.0;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The tracked state with `turn` set to the player about to act, and how far into
+/// their turn they are.
+#[derive(Debug, Clone)]
+pub struct SearchPosition {
+    pub state: CubirdsState,
+    pub phase: TurnPhase,
+}
+
+/// First plies that reach the same position, rolled out as one.
+#[derive(Debug, Clone)]
+struct Arm {
+    plies: Vec<PlyMove>,
+    /// Rollouts started, including those that couldn't finish.
+    tries: usize,
+    visits: usize,
+    wins: f64,
+}
+
+/// Spreads rollouts over the mover's first plies by their upper confidence bounds,
+/// so plies that are clearly losing stop taking rollouts from the contenders.
+#[derive(Debug, Clone)]
+struct Bandit {
+    mover: usize,
+    arms: Vec<Arm>,
+    tries: usize,
+}
+
+impl Bandit {
+    /// One arm per class of equivalent first plies in `position`, counting the rollouts
+    /// `tree` already holds. None unless the mover's hand is known, as otherwise which
+    /// plies they have depends on the deal.
+    fn new(position: &SearchPosition, sampler: &DealSampler, tree: &SearchNode) -> Option<Bandit> {
+        let mover = position.state.turn;
+        let cards = &position.state.players.get(mover)?.cards;
+        if cards.known_cards.total() != cards.total_cards && cards.min_cards != cards.max_cards {
+            return None;
+        }
+        let sampled = MaterializedCubirdsState::sample_from(&position.state, sampler);
+        let mut classes: HashMap<PlyMove, usize> = HashMap::new();
+        let mut arms: Vec<Arm> = Vec::new();
+        for ply in sampled.first_plies(&position.phase) {
+            let key = match (&ply, &position.phase) {
+                (PlyMove::Line(line_move), TurnPhase::Play) => PlyMove::Line(line_move.canonical(&position.state.board)),
+                _ => ply.clone(),
+            };
+            let idx = *classes.entry(key).or_insert_with(|| {
+                arms.push(Arm{plies: Vec::new(), tries: 0, visits: 0, wins: 0.0});
+                return arms.len() - 1;
+            });
+            if let Some(child) = tree.children.get(&ply) {
+                arms[idx].visits += child.visits as usize;
+                arms[idx].wins += child.win_rate(mover) * child.visits as f64;
+            }
+            arms[idx].plies.push(ply);
+        }
+        if arms.is_empty() {
+            return None;
+        }
+        for arm in &mut arms {
+            arm.tries = arm.visits;
+        }
+        let tries = arms.iter().map(|x| x.tries).sum();
+        return Some(Bandit{mover, arms, tries});
+    }
+
+    /// Picks the arm to roll out next and the ply to play for it, taking turns
+    /// among the arm's plies.
+    fn choose(&mut self) -> (usize, PlyMove) {
+        let log_tries = (self.tries.max(1) as f64).ln();
+        let bound = |arm: &Arm| {
+            if arm.tries < MIN_ARM_ROLLOUTS {
+                return f64::INFINITY;
+            }
+            let mean = if arm.visits == 0 { 0.0 } else { arm.wins / arm.visits as f64 };
+            return mean + EXPLORATION * (log_tries / arm.tries as f64).sqrt();
+        };
+        let mut best = 0;
+        for idx in 1..self.arms.len() {
+            let (arm, leader) = (&self.arms[idx], &self.arms[best]);
+            if bound(arm) > bound(leader) || (bound(arm) == bound(leader) && arm.tries < leader.tries) {
+                best = idx;
+            }
+        }
+        let arm = &mut self.arms[best];
+        let ply = arm.plies[arm.tries % arm.plies.len()].clone();
+        arm.tries += 1;
+        self.tries += 1;
+        return (best, ply);
+    }
+
+    /// Counts a rollout of `arm` that `winner` won, or that couldn't finish.
+    fn record(&mut self, arm: usize, winner: Option<usize>) {
+        if let Some(winner) = winner {
+            self.arms[arm].visits += 1;
+            self.arms[arm].wins += (winner == self.mover) as usize as f64;
+        }
+    }
+}
+
+/// Every consistent deal paired with each first ply the mover has in it, for when
+/// few enough deals are consistent to go through them all. Rollouts take the pairs
+/// in turn, deal by deal, and count for their deal's probability times the number of
+/// deals, so each ply's statistics weigh the deals exactly, every ply is tried on the
+/// same deals, and a rollout weighs one on average, like a sampled one.
+struct Sweep {
+    deals: Vec<(Deal, f64)>,
+    units: Vec<(usize, PlyMove)>,
+}
+
+impl Sweep {
+    fn new(position: &SearchPosition, sampler: &DealSampler) -> Option<Sweep> {
+        let deals = sampler.deals()?;
+        let mut units = Vec::new();
+        for (idx, (deal, _)) in deals.iter().enumerate() {
+            let dealt = MaterializedCubirdsState::from_deal(&position.state, deal.clone(), StdRng::seed_from_u64(0));
+            units.extend(dealt.first_plies(&position.phase).into_iter().map(|x| (idx, x)));
+        }
+        if units.is_empty() {
+            return None;
+        }
+        return Some(Sweep{deals, units});
+    }
+
+    /// The deal, first ply and weight of the `rollout`th rollout.
+    fn unit(&self, rollout: usize) -> (&Deal, &PlyMove, f64) {
+        let (deal, ply) = &self.units[rollout % self.units.len()];
+        let (deal, probability) = &self.deals[*deal];
+        return (deal, ply, *probability * self.deals.len() as f64);
+    }
+}
+
+struct SearchShared {
+    stop: AtomicBool,
+    /// Rollouts started so far, numbering each one's place in the sweep.
+    rollouts: AtomicUsize,
+    tree: Mutex<SearchNode>,
+    /// Chooses each rollout's first ply, if allocating adaptively.
+    bandit: Option<Mutex<Bandit>>,
+    /// Sets up every rollout, if the deals are enumerated.
+    sweep: Option<Sweep>,
+    /// Every player's exact chance to win after each first ply, once solved.
+    solved: Mutex<Option<HashMap<PlyMove, Vec<f64>>>>,
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+/// A running evaluation. Workers merge their rollouts into the shared tree after
+/// every batch, so the tree can be inspected at any time and the search stopped early.
+#[derive(Clone)]
+pub struct Search {
+    shared: Arc<SearchShared>,
+}
+
+/// Marks a worker as finished even if its rollouts panic, so waiters never hang.
+struct FinishGuard<'a>(&'a SearchShared);
+
+impl Drop for FinishGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.finished.notify_all();
+    }
+}
+
+impl Search {
+    pub fn stop(&self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+    }
+
+    pub fn with_tree<R>(&self, f: impl FnOnce(&SearchNode) -> R) -> R {
+        f(&self.shared.tree.lock().unwrap())
+    }
+
+    /// The endgame solver's result, if it solved the position.
+    pub fn solved(&self) -> Option<HashMap<PlyMove, Vec<f64>>> {
+        return self.shared.solved.lock().unwrap().clone();
+    }
+
+    /// Waits up to `timeout` for every worker to finish, returning whether they have.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let running = self.shared.running.lock().unwrap();
+        let (running, _) = self.shared.finished.wait_timeout_while(running, timeout, |x| *x > 0).unwrap();
+        return *running == 0;
+    }
+
+    /// Stops the search, waits for the workers and takes the tree they built.
+    pub fn finish(self) -> SearchNode {
+        self.stop();
+        let running = self.shared.running.lock().unwrap();
+        drop(self.shared.finished.wait_while(running, |x| *x > 0).unwrap());
+        return std::mem::take(&mut *self.shared.tree.lock().unwrap());
+    }
+}
+
+fn internal_evaluate_state(position: &SearchPosition, sampler: &DealSampler, deadline: Instant, shared: &SearchShared) {
+    while Instant::now() < deadline && !shared.stop.load(Ordering::Relaxed) {
+        let choices: Vec<Option<(usize, PlyMove)>> = match &shared.bandit {
+            Some(bandit) => {
+                let mut bandit = bandit.lock().unwrap();
+                (0..BATCH_ROLLOUTS).map(|_| Some(bandit.choose())).collect()
+            },
+            None => vec![None; BATCH_ROLLOUTS],
+        };
+        let mut rollouts: Vec<(Vec<PlyMove>, usize, f64)> = Vec::with_capacity(BATCH_ROLLOUTS);
+        let mut outcomes: Vec<(usize, Option<usize>)> = Vec::new();
+        for choice in choices {
+            let rollout = shared.rollouts.fetch_add(1, Ordering::Relaxed);
+            let mut rng = StdRng::from_rng(thread_rng()).unwrap();
+            let (deal, first, weight) = match &shared.sweep {
+                Some(sweep) => {
+                    let (deal, ply, weight) = sweep.unit(rollout);
+                    let mut deal = deal.clone();
+                    deal.deck.shuffle(&mut rng);
+                    (deal, Some(ply.clone()), weight)
+                },
+                None => (sampler.sample(&mut rng), choice.as_ref().map(|x| x.1.clone()), 1.0),
+            };
+            let mut sampled = MaterializedCubirdsState::from_deal(&position.state, deal, rng);
+            let mut path = Vec::new();
+            let winner = match &first {
+                Some(ply) => sampled.forced_rollout(&position.phase, ply, &mut path),
+                None => sampled.rollout(&position.phase, &mut path),
+            };
+            let winner = winner.map(|x| x as usize);
+            if let Some((arm, _)) = choice {
+                outcomes.push((arm, winner));
+            }
+            if let Some(winner) = winner {
+                rollouts.push((path, winner, weight));
+            }
+        }
+        let mut tree = shared.tree.lock().unwrap();
+        for (path, winner, weight) in rollouts {
+            tree.record_weighted(&path, winner, weight);
+        }
+        drop(tree);
+        if let Some(bandit) = &shared.bandit {
+            let mut bandit = bandit.lock().unwrap();
+            for (arm, winner) in outcomes {
+                bandit.record(arm, winner);
+            }
+        }
+    }
+}
+
+/// Worker threads shared by every kind of evaluation. Each query is split into one
+/// job per worker, and idle workers take the next queued job, so whichever query is
+/// running gets every core.
+pub struct EvaluationPool {
+    size: usize,
+    jobs: Option<mpsc::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl EvaluationPool {
+    pub fn new(size: usize) -> EvaluationPool {
+        let size = size.max(1);
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        let mut workers = Vec::new();
+        for _ in 0..size {
+            let rx = Arc::clone(&rx);
+            workers.push(thread::spawn(move || loop {
+                let job = rx.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            }));
+        }
+
+        return EvaluationPool{
+            size,
+            jobs: Some(tx),
+            workers,
+        };
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Searches `position` for up to `duration`, adding to the statistics already in `tree`.
+    /// Hidden cards are dealt by `sampler`, built from the position's state. If it lists
+    /// every consistent deal, rollouts sweep each of them with each first ply, starting
+    /// from an empty tree so sampled rollouts don't skew the weighting. Otherwise,
+    /// if `adaptive` and the mover's hand is known, rollouts go mostly to the most
+    /// promising first plies rather than to whichever ply random play picks.
+    /// With a `solver`, one worker first tries to solve the position exactly, which
+    /// stops the search if it succeeds, and rolls out like the others if it gives up.
+    pub fn start(&self, position: SearchPosition, sampler: DealSampler, tree: SearchNode, duration: Duration, adaptive: bool, mut solver: Option<Arc<Mutex<EndgameSolver>>>) -> Search {
+        let sweep = Sweep::new(&position, &sampler);
+        let tree = if sweep.is_some() { SearchNode::new() } else { tree };
+        let bandit = if adaptive && sweep.is_none() { Bandit::new(&position, &sampler, &tree) } else { None };
+        let sampler = Arc::new(sampler);
+        let position = Arc::new(position);
+        let deadline = Instant::now() + duration;
+        let shared = Arc::new(SearchShared{
+            stop: AtomicBool::new(false),
+            rollouts: AtomicUsize::new(0),
+            tree: Mutex::new(tree),
+            bandit: bandit.map(Mutex::new),
+            sweep,
+            solved: Mutex::new(None),
+            running: Mutex::new(self.size),
+            finished: Condvar::new(),
+        });
+
+        for _ in 0..self.size {
+            let position = Arc::clone(&position);
+            let sampler = Arc::clone(&sampler);
+            let shared = Arc::clone(&shared);
+            let solver = solver.take();
+            let job: Job = Box::new(move || {
+                let _guard = FinishGuard(&shared);
+                if let Some(solver) = solver {
+                    let cancel = Arc::clone(&shared);
+                    let cancelled = move || cancel.stop.load(Ordering::Relaxed) || Instant::now() >= deadline;
+                    if let Some(solved) = solver.lock().unwrap().solve_deal(&position, &sampler, cancelled) {
+                        *shared.solved.lock().unwrap() = Some(solved);
+                        shared.stop.store(true, Ordering::Relaxed);
+                    }
+                }
+                internal_evaluate_state(&position, &sampler, deadline, &shared);
+            });
+            self.jobs.as_ref().unwrap().send(job).unwrap();
+        }
+
+        return Search{shared};
+    }
+}
+
+impl Drop for EvaluationPool {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::sink;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use crate::bird::Bird;
+    use crate::bird_counts::BirdCounts;
+    use crate::line::Line;
+    use crate::materialized_state::{FlockMove, LineMove};
+    use crate::partial_cards::PartialCards;
+    use crate::player::Player;
+    use super::*;
+
+    fn position(setup: &str) -> SearchPosition {
+        let state = CubirdsState::read_setup(&mut setup.as_bytes(), &mut sink()).unwrap();
+        return SearchPosition{state, phase: TurnPhase::Play};
+    }
+
+    #[test]
+    fn solves_known_endgames_in_the_pool() {
+        // Every card is known, the rest discarded, and player 0 wins only by drawing the
+        // last flamingo.
+        let cards = |x: &str| BirdCounts::from_birds(&Bird::from_string(x).unwrap());
+        let player = |collection: &str, hand: &str| Player{collection: cards(collection), cards: PartialCards::revealed(cards(hand))};
+        let mut state = CubirdsState::new();
+        state.board = ["PWR", "WRM", "RMD", "MDP"].map(|x| Line::from_birds(&Bird::from_string(x).unwrap()));
+        state.players = vec![player("OOOF", "FFTP"), player("FFFOOTT", "OOOTTT")];
+        state.draw_pile = PartialCards::revealed(cards("FPPP"));
+        let used = cards("PWRWRMRMDMDPOOOFFFTPFFFOOTTOOOTTTFPPP");
+        state.discard_pile = vec![PartialCards::revealed(BirdCounts::full_deck() - used)];
+        let position = SearchPosition{state, phase: TurnPhase::Play};
+        let sampler = DealSampler::new(&position.state).unwrap();
+
+        let pool = EvaluationPool::new(2);
+        let solver = Arc::new(Mutex::new(EndgameSolver::new()));
+        let search = pool.start(position, sampler, SearchNode::new(), Duration::from_secs(60), false, Some(solver));
+        assert!(search.wait_timeout(Duration::from_secs(60)));
+        let solved = search.solved().unwrap();
+        assert!(solved.values().any(|x| (x[0] - 0.5).abs() < 1e-9));
+    }
+
+    #[test]
+    fn pulls_equivalent_plies_together() {
+        let ours = position("3\n0\n0\nPPRROFWM\nP\nO\nD\nRRW\nWRR\nFMM\nMWW\n");
+        let sampler = DealSampler::new(&ours.state).unwrap();
+        let bandit = Bandit::new(&ours, &sampler, &SearchNode::new()).unwrap();
+        let plies: usize = bandit.arms.iter().map(|x| x.plies.len()).sum();
+        assert!(bandit.arms.len() < plies);
+        let robin = |line: usize, left: bool| PlyMove::Line(LineMove{line, bird: Bird::ROBIN, left, draw: false});
+        assert!(bandit.arms.iter().any(|x| x.plies.contains(&robin(0, true)) && x.plies.contains(&robin(1, false))));
+        assert!(bandit.arms.iter().all(|x| x.plies.len() == 1 || !x.plies.contains(&robin(2, true))));
+
+        let theirs = position("3\n1\n0\nPPRROFWM\nP\nO\nD\nRRW\nWRR\nFMM\nMWW\n");
+        assert!(Bandit::new(&theirs, &DealSampler::new(&theirs.state).unwrap(), &SearchNode::new()).is_none());
+    }
+
+    #[test]
+    fn sweeps_every_deal_by_its_probability() {
+        let mut ours = position("2\n0\n0\nPPRROFWM\nP\nO\nRRW\nPPO\nFMM\nMWW\n");
+        for bird in [Bird::PARROT, Bird::WARBLER, Bird::ROBIN, Bird::FLAMINGO, Bird::MAGPIE, Bird::OWL] {
+            ours.state.players[1].cards.max_cards[bird] = 0;
+        }
+        let sampler = DealSampler::new(&ours.state).unwrap();
+        let deals = sampler.deals().unwrap();
+        assert!(deals.len() > 2);
+        let sweep = Sweep::new(&ours, &sampler).unwrap();
+
+        // Rollouts whose winner only depends on the deal.
+        let winner = |deal: &Deal| if deal.hands[1][Bird::DUCK] > 4 { 0 } else { 1 };
+        let mut tree = SearchNode::new();
+        for rollout in 0..3 * sweep.units.len() {
+            let (deal, ply, weight) = sweep.unit(rollout);
+            tree.record_weighted(std::slice::from_ref(ply), winner(deal), weight);
+        }
+        let expected: f64 = deals.iter().filter(|x| winner(&x.0) == 0).map(|x| x.1).sum();
+        assert!(0.0 < expected && expected < 1.0);
+        let plies = MaterializedCubirdsState::from_deal(&ours.state, deals[0].0.clone(), StdRng::seed_from_u64(0)).first_plies(&ours.phase);
+        assert_eq!(tree.children.len(), plies.len());
+        for ply in plies {
+            let child = &tree.children[&ply];
+            assert_eq!(child.visits as usize, 3 * deals.len());
+            assert!((child.win_rate(0) - expected).abs() < 1e-9, "{:?}: {} vs {}", ply, child.win_rate(0), expected);
+        }
+    }
+
+    #[test]
+    fn sweeps_start_from_an_empty_tree() {
+        let mut ours = position("2\n0\n0\nPPRROFWM\nP\nO\nRRW\nPPO\nFMM\nMWW\n");
+        for bird in [Bird::PARROT, Bird::WARBLER, Bird::ROBIN, Bird::FLAMINGO, Bird::MAGPIE, Bird::OWL] {
+            ours.state.players[1].cards.max_cards[bird] = 0;
+        }
+        let sampler = DealSampler::new(&ours.state).unwrap();
+        assert!(sampler.deals().is_some());
+
+        // Sampled rollouts from an earlier search, which a sweep drops and a sampled
+        // search adds to. Neither search gets to roll out.
+        let mut tree = SearchNode::new();
+        tree.record(&[PlyMove::Flock(FlockMove{bird: None})], 0);
+        let pool = EvaluationPool::new(1);
+        let search = pool.start(ours, sampler, tree.clone(), Duration::ZERO, false, None);
+        assert_eq!(search.finish().visits, 0);
+        let theirs = position("2\n0\n0\nPPRROFWM\nP\nO\nRRW\nPPO\nFMM\nMWW\n");
+        let sampler = DealSampler::new(&theirs.state).unwrap();
+        assert!(sampler.deals().is_none());
+        let search = pool.start(theirs, sampler, tree, Duration::ZERO, false, None);
+        assert_eq!(search.finish().visits, 1);
+    }
+
+    #[test]
+    fn concentrates_on_the_leading_plies() {
+        let chances = [0.6, 0.55, 0.3, 0.2, 0.1, 0.1, 0.05, 0.0];
+        let arms = (0..chances.len())
+            .map(|_| Arm{plies: vec![PlyMove::Flock(FlockMove{bird: None})], tries: 0, visits: 0, wins: 0.0})
+            .collect();
+        let mut bandit = Bandit{mover: 0, arms, tries: 0};
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..5000 {
+            let (arm, _) = bandit.choose();
+            let winner = if rng.gen_bool(chances[arm]) { 0 } else { 1 };
+            bandit.record(arm, Some(winner));
+        }
+        let leading = bandit.arms[0].tries + bandit.arms[1].tries;
+        assert!(leading > 5000 * 4 / 5, "only {} rollouts went to the leaders", leading);
+        assert!(bandit.arms.iter().all(|x| x.tries >= MIN_ARM_ROLLOUTS && x.tries <= bandit.arms[0].tries));
+        assert_eq!(bandit.arms.iter().map(|x| x.visits).sum::<usize>(), 5000);
+    }
+}
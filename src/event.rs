@@ -0,0 +1,36 @@
+use crate::bird::Bird;
+
+/// A publicly observable game event, in the form the tracked state consumes it.
+/// Cards that only their owner sees (draws, newly dealt hands) are optional.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum GameEvent {
+    Play {
+        player: usize,
+        bird: Bird,
+        count: i32,
+        line: usize,
+        left: bool,
+        refill: Option<(Vec<Bird>, bool)>,
+    },
+    Draw {
+        player: usize,
+        birds: Option<(Bird, Bird)>,
+    },
+    Fly {
+        player: usize,
+        bird: Bird,
+        hand_size: i32,
+        small: bool,
+    },
+    /// Cards dealt onto a line a sandwich left with one species, in line order.
+    Refill {
+        line: usize,
+        birds: Vec<Bird>,
+        left: bool,
+    },
+    Reset,
+    Hand {
+        player: usize,
+        birds: Vec<Bird>,
+    },
+}
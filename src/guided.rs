@@ -0,0 +1,221 @@
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+use crate::bird::Bird;
+use crate::command_handler::CommandHandler;
+use crate::event::GameEvent;
+use crate::materialized_state::TurnPhase;
+
+/// Called with the handler when our turn reaches a decision, typically to start an
+/// evaluation, which is waited on before the next question.
+pub type Hook = Box<dyn FnMut(&mut CommandHandler)>;
+
+/// Tracks a game by asking about each turn one question at a time, feeding the
+/// answers to the handler as events.
+pub struct GuidedSession<R: BufRead, W: Write> {
+    pub handler: CommandHandler,
+    input: R,
+    output: W,
+    before_play: Hook,
+    before_fly: Hook,
+}
+
+impl<R: BufRead, W: Write> GuidedSession<R, W> {
+    pub fn from(handler: CommandHandler, input: R, output: W, before_play: Hook, before_fly: Hook) -> GuidedSession<R, W> {
+        return GuidedSession{
+            handler,
+            input,
+            output,
+            before_play,
+            before_fly,
+        };
+    }
+
+    /// Asks `question` until the answer parses, failing only once the input ends.
+    fn ask<T>(&mut self, question: &str, parse: impl Fn(&str) -> Option<T>) -> Result<T, String> {
+        loop {
+            let _ = writeln!(self.output, "{}", question);
+            let mut answer = String::new();
+            match self.input.read_line(&mut answer) {
+                Ok(0) => return Err(String::from("Input ended.")),
+                Ok(_) => {},
+                Err(error) => return Err(error.to_string()),
+            }
+            if let Some(parsed) = parse(answer.trim_end_matches(['\n', '\r'])) {
+                return Ok(parsed);
+            }
+            let _ = writeln!(self.output, "Invalid answer '{}'.", answer.trim_end());
+        }
+    }
+
+    fn ask_yes_no(&mut self, question: &str) -> Result<bool, String> {
+        return self.ask(&format!("{} (Y/N)", question), |x| match x {
+            "Y" => Some(true),
+            "N" => Some(false),
+            _ => None,
+        });
+    }
+
+    fn ask_number(&mut self, question: &str) -> Result<usize, String> {
+        return self.ask(question, |x| usize::from_str(x).ok());
+    }
+
+    fn ask_bird(&mut self, question: &str) -> Result<Bird, String> {
+        return self.ask(question, |x| Bird::from_string(x).filter(|x| x.len() == 1).map(|x| x[0]));
+    }
+
+    fn ask_birds(&mut self, question: &str) -> Result<Vec<Bird>, String> {
+        return self.ask(question, Bird::from_string);
+    }
+
+    fn ask_direction(&mut self, question: &str) -> Result<bool, String> {
+        return self.ask(&format!("{} (L/R)", question), |x| match x {
+            "L" => Some(true),
+            "R" => Some(false),
+            _ => None,
+        });
+    }
+
+    /// Submits the event `ask` builds from the answers, starting over while the handler
+    /// rejects it. Returns whether there was an event at all.
+    fn ask_event(&mut self, ask: impl Fn(&mut Self) -> Result<Option<GameEvent>, String>) -> Result<bool, String> {
+        loop {
+            let Some(event) = ask(self)? else {
+                return Ok(false);
+            };
+            match self.handler.submit(event) {
+                Ok(()) => return Ok(true),
+                Err(error) => {
+                    let _ = writeln!(self.output, "{}", error);
+                },
+            }
+        }
+    }
+
+    /// Walks through one turn: the play and its refill, the draw, the flock, and our
+    /// new hand if the turn ended the round. Fails only once the input ends.
+    pub fn next_turn(&mut self) -> Result<(), String> {
+        let player = self.handler.state.turn;
+        let our_seat = self.handler.state.player_idx as usize;
+        let _ = writeln!(self.output, "Turn {}", player);
+
+        if player == our_seat {
+            (self.before_play)(&mut self.handler);
+            self.handler.wait_search();
+        }
+        self.ask_event(|x| {
+            let bird = x.ask_bird("Bird placed:")?;
+            let count = x.ask_number("Bird count:")? as i32;
+            let line = x.ask_number("Line:")?;
+            let left = x.ask_direction("Direction:")?;
+            return Ok(Some(GameEvent::Play{player, bird, count, line, left, refill: None}));
+        })?;
+
+        if let Some(line) = self.handler.state.pending_refill {
+            self.ask_event(|x| {
+                let birds = x.ask_birds("Birds new on line:")?;
+                let left = x.ask_direction("New birds direction:")?;
+                return Ok(Some(GameEvent::Refill{line, birds, left}));
+            })?;
+        }
+
+        if let TurnPhase::Draw(_) = self.handler.phase() {
+            self.ask_event(|x| {
+                if !x.ask_yes_no("Did player draw?")? {
+                    return Ok(None);
+                }
+                let mut birds = None;
+                if player == our_seat {
+                    birds = Some(x.ask("Cards drawn:", |x| Bird::from_string(x).filter(|x| x.len() == 2).map(|x| (x[0], x[1])))?);
+                }
+                return Ok(Some(GameEvent::Draw{player, birds}));
+            })?;
+        }
+
+        let mut flew = false;
+        if self.handler.state.players[player].cards.total_cards > 0 {
+            if player == our_seat {
+                (self.before_fly)(&mut self.handler);
+                self.handler.wait_search();
+            }
+            flew = self.ask_event(|x| {
+                if !x.ask_yes_no("Did player fly home?")? {
+                    return Ok(None);
+                }
+                let bird = x.ask_bird("Bird flown home:")?;
+                let small = x.ask("Flock size: (S/L)", |x| match x {
+                    "S" => Some(true),
+                    "L" => Some(false),
+                    _ => None,
+                })?;
+                let hand_size = x.ask_number("Cards left in hand:")? as i32;
+                return Ok(Some(GameEvent::Fly{player, bird, hand_size, small}));
+            })?;
+        }
+        if !flew {
+            self.handler.end_turn()?;
+        }
+
+        if self.handler.state.pending_hand {
+            self.ask_event(|x| {
+                let birds = x.ask_birds("Player hand:")?;
+                return Ok(Some(GameEvent::Hand{player: our_seat, birds}));
+            })?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::sink;
+    use std::time::{Duration, Instant};
+    use crate::command_handler::tests::handler;
+    use crate::command_handler::SearchOptions;
+    use crate::STARTING_CARDS_HAND;
+    use super::*;
+
+    fn session(answers: &str) -> GuidedSession<&[u8], std::io::Sink> {
+        let handler = handler(SearchOptions::default());
+        return GuidedSession::from(handler, answers.as_bytes(), sink(), Box::new(|_| {}), Box::new(|_| {}));
+    }
+
+    #[test]
+    fn follows_turns_through_the_questions() {
+        // Player 1 plays a robin onto line 0, mistypes the direction, draws and passes.
+        let mut guided = session("R\n1\n0\nX\nL\nY\nN\n");
+        guided.next_turn().unwrap();
+        assert_eq!(guided.handler.state.turn, 2);
+        assert_eq!(guided.handler.state.players[1].cards.total_cards, STARTING_CARDS_HAND + 1);
+
+        // Player 1 plays all eight cards without drawing, which ends the round.
+        let mut guided = session("R\n8\n0\nL\nN\nPPRROFWM\n");
+        guided.next_turn().unwrap();
+        assert!(!guided.handler.state.pending_hand);
+        assert_eq!(guided.handler.state.turn, 2);
+        assert_eq!(guided.handler.state.players[0].cards.known_cards.total(), STARTING_CARDS_HAND);
+    }
+
+    #[test]
+    fn evaluates_before_asking() {
+        // Players 1 and 2 play a robin and draw, then our turn starts with a search.
+        let handler = handler(SearchOptions::default());
+        let play_score: Hook = Box::new(|x| { let _ = x.execute("PLAYSCORE 1"); });
+        let answers = "R\n1\n0\nL\nY\nN\nR\n1\n0\nL\nY\nN\n";
+        let mut guided = GuidedSession::from(handler, answers.as_bytes(), Vec::new(), play_score, Box::new(|_| {}));
+        guided.next_turn().unwrap();
+        guided.next_turn().unwrap();
+
+        let asked = guided.output.len();
+        let started = Instant::now();
+        assert!(guided.next_turn().is_err());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(String::from_utf8_lossy(&guided.output[asked..]).contains("Bird placed:"));
+    }
+
+    #[test]
+    fn stops_when_the_input_ends() {
+        let mut guided = session("R\n1\n");
+        assert!(guided.next_turn().is_err());
+        assert_eq!(guided.handler.state.players[1].cards.total_cards, STARTING_CARDS_HAND);
+    }
+}
@@ -0,0 +1,20 @@
+#![allow(clippy::needless_return, clippy::upper_case_acronyms, clippy::new_without_default)]
+
+pub mod bird;
+pub mod bird_counts;
+pub mod command_handler;
+pub mod deal_sampler;
+pub mod endgame;
+pub mod evaluation;
+pub mod event;
+pub mod guided;
+pub mod player;
+pub mod line;
+pub mod state;
+pub mod materialized_state;
+pub mod partial_cards;
+pub mod search_tree;
+pub mod transposition;
+
+pub const LINES: usize = 4;
+pub const STARTING_CARDS_HAND: i32 = 8;
@@ -1,69 +1,476 @@
-use std::collections::HashMap;
-use rand::{Rng, thread_rng};
+use std::collections::VecDeque;
+use std::fmt;
+use rand::Rng;
 use crate::bird::Bird;
+use crate::bird_counts::BirdCounts;
 use crate::materialized_state::MaterializedCubirdsState;
+use crate::transposition::hash_of;
 
-#[derive(Debug, Clone)]
-pub struct Line(pub Vec<Bird>);
+#[derive(Clone, Eq, PartialEq, Hash)]
+pub struct Line {
+    runs: VecDeque<(Bird, i32)>,
+}
 
 impl Line {
     pub fn new() -> Line {
-        Line(Vec::new())
+        Line{
+            runs: VecDeque::new(),
+        }
     }
 
-    pub fn sandwich(&mut self, left: bool) -> Option<HashMap<Bird, i32>> {
-        let reference: i32 = if left { 0 } else { self.0.len() as i32 - 1 };
-        let movement: i32 = if left { 1 } else { -1 };
-        let mut start_index = reference;
-        while self.0[start_index as usize] == self.0[reference as usize] {
-            start_index += movement;
-        }
-        let mut end_index = start_index + movement;
-        while end_index >= 0 && end_index < self.0.len() as i32 && self.0[end_index as usize] != self.0[reference as usize] {
-            end_index += movement;
+    pub fn from_birds(birds: &[Bird]) -> Line {
+        let mut line = Line::new();
+        line.extend(birds, false);
+        return line;
+    }
+
+    pub fn birds(&self) -> impl Iterator<Item = Bird> + '_ {
+        self.runs.iter().flat_map(|(bird, count)| std::iter::repeat_n(*bird, *count as usize))
+    }
+
+    pub fn counts(&self) -> BirdCounts {
+        let mut counts = BirdCounts::new();
+        for (bird, count) in &self.runs {
+            counts[*bird] += *count;
         }
-        if end_index >= 0 && end_index < self.0.len() as i32 {
-            let mut birds = HashMap::new();
-            let lower_bound = if left { start_index } else { end_index + 1 };
-            let upper_bound = if left { end_index } else { start_index + 1 };
-            let mut i = lower_bound;
-            while i < upper_bound {
-                let bird = self.0[lower_bound as usize];
-                *birds.entry(bird).or_insert(0) += 1;
-                self.0.remove(lower_bound as usize);
-                i += 1;
+        return counts;
+    }
+
+    pub fn len(&self) -> i32 {
+        self.runs.iter().map(|x| x.1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+
+    fn push(&mut self, bird: Bird, count: i32, left: bool) {
+        let end = if left { self.runs.front_mut() } else { self.runs.back_mut() };
+        if let Some(run) = end {
+            if run.0 == bird {
+                run.1 += count;
+                return;
             }
-            return Some(birds);
         }
-        return None
+        if left {
+            self.runs.push_front((bird, count));
+        } else {
+            self.runs.push_back((bird, count));
+        }
     }
 
-    pub fn play(&mut self, bird: Bird, count: i32, left: bool) -> Option<HashMap<Bird, i32>> {
+    /// The line read from its other end.
+    pub fn reversed(&self) -> Line {
+        Line{
+            runs: self.runs.iter().rev().copied().collect(),
+        }
+    }
+
+    /// A hash that is the same read from either end.
+    pub fn canonical_hash(&self) -> u64 {
+        return hash_of(self).min(hash_of(self.reversed()));
+    }
+
+    /// Whether the line is down to one species and must be refilled.
+    pub fn needs_refill(&self) -> bool {
+        self.runs.len() < 2
+    }
+
+    /// Adds birds to one end of the line, keeping their order: `birds[0]` ends up
+    /// leftmost when `left` is set, and nearest the existing birds otherwise.
+    pub fn extend(&mut self, birds: &[Bird], left: bool) {
         if left {
-            for _ in 0..count {
-                self.0.insert(0, bird);
+            for bird in birds.iter().rev() {
+                self.push(*bird, 1, true);
             }
         } else {
-            for _ in 0..count {
-                self.0.push(bird);
+            for bird in birds {
+                self.push(*bird, 1, false);
             }
         }
+    }
+
+    pub fn sandwich(&mut self, left: bool) -> Option<BirdCounts> {
+        let runs = self.runs.len();
+        if runs < 3 {
+            return None;
+        }
+        let run_at = |idx: usize| if left { idx } else { runs - 1 - idx };
+        let reference = self.runs[run_at(0)].0;
+        let closing = (2..runs).find(|idx| self.runs[run_at(*idx)].0 == reference)?;
+
+        let mut birds = BirdCounts::new();
+        let range = if left { 1..closing } else { (runs - closing)..(runs - 1) };
+        for (bird, count) in self.runs.drain(range) {
+            birds[bird] += count;
+        }
+
+        let (outer, inner) = if left { (0, 1) } else { (runs - closing, runs - closing - 1) };
+        let merged = self.runs[outer].1;
+        self.runs[inner].1 += merged;
+        self.runs.remove(outer);
+        return Some(birds);
+    }
+
+    pub fn play(&mut self, bird: Bird, count: i32, left: bool) -> Option<BirdCounts> {
+        self.push(bird, count, left);
 
         return self.sandwich(left);
     }
 
-    pub fn draw_new(&mut self, left: bool, draw_pile: &mut Vec<Bird>, discard_pile: &mut HashMap<Bird, i32>) -> bool {
-        while self.0[0] == *self.0.last().unwrap() {
-            if let Some(drawn) = MaterializedCubirdsState::draw(draw_pile, discard_pile) {
-                if left {
-                    self.0.insert(0, drawn);
+    /// Refills a line left with a single species, drawing until a second species appears.
+    /// Returns the birds added, in line order, or `None` if the cards ran out.
+    pub fn draw_new<R: Rng>(&mut self, left: bool, draw_pile: &mut Vec<Bird>, discard_pile: &mut BirdCounts, rng: &mut R) -> Option<Vec<Bird>> {
+        let mut drawn_birds = Vec::new();
+        while self.needs_refill() {
+            let drawn = MaterializedCubirdsState::draw(draw_pile, discard_pile, rng)?;
+            self.push(drawn, 1, left);
+            drawn_birds.push(drawn);
+        }
+        if left {
+            drawn_birds.reverse();
+        }
+        return Some(drawn_birds);
+    }
+}
+
+impl fmt::Debug for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let birds: String = self.birds().map(|x| x.to_char()).collect();
+        f.debug_tuple("Line").field(&birds).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use strum::IntoEnumIterator;
+    use super::*;
+
+    /// The original `Vec<Bird>` line, kept as the reference for the run-length encoding.
+    struct VecLine(Vec<Bird>);
+
+    impl VecLine {
+        fn sandwich(&mut self, left: bool) -> Option<BirdCounts> {
+            let reference: i32 = if left { 0 } else { self.0.len() as i32 - 1 };
+            let movement: i32 = if left { 1 } else { -1 };
+            let mut start_index = reference;
+            while self.0[start_index as usize] == self.0[reference as usize] {
+                start_index += movement;
+            }
+            let mut end_index = start_index + movement;
+            while end_index >= 0 && end_index < self.0.len() as i32 && self.0[end_index as usize] != self.0[reference as usize] {
+                end_index += movement;
+            }
+            if end_index >= 0 && end_index < self.0.len() as i32 {
+                let mut birds = BirdCounts::new();
+                let lower_bound = if left { start_index } else { end_index + 1 };
+                let upper_bound = if left { end_index } else { start_index + 1 };
+                let mut i = lower_bound;
+                while i < upper_bound {
+                    let bird = self.0[lower_bound as usize];
+                    birds[bird] += 1;
+                    self.0.remove(lower_bound as usize);
+                    i += 1;
+                }
+                return Some(birds);
+            }
+            return None
+        }
+
+        fn play(&mut self, bird: Bird, count: i32, left: bool) -> Option<BirdCounts> {
+            if left {
+                for _ in 0..count {
+                    self.0.insert(0, bird);
+                }
+            } else {
+                for _ in 0..count {
+                    self.0.push(bird);
+                }
+            }
+
+            return self.sandwich(left);
+        }
+
+        fn draw_new(&mut self, left: bool, draw_pile: &mut Vec<Bird>, discard_pile: &mut BirdCounts, rng: &mut StdRng) -> bool {
+            while self.0[0] == *self.0.last().unwrap() {
+                if let Some(drawn) = MaterializedCubirdsState::draw(draw_pile, discard_pile, rng) {
+                    if left {
+                        self.0.insert(0, drawn);
+                    } else {
+                        self.0.push(drawn);
+                    }
                 } else {
-                    self.0.push(drawn);
+                    return false;
+                }
+            }
+            return true;
+        }
+    }
+
+    fn random_bird(rng: &mut StdRng, species: usize) -> Bird {
+        Bird::iter().nth(rng.gen_range(0..species)).unwrap()
+    }
+
+    /// Random line with at least two species, so the reference never walks off the end.
+    fn random_line(rng: &mut StdRng, species: usize) -> Vec<Bird> {
+        loop {
+            let length = rng.gen_range(2..12);
+            let birds: Vec<Bird> = (0..length).map(|_| random_bird(rng, species)).collect();
+            if birds.iter().any(|x| *x != birds[0]) {
+                return birds;
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_birds() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..1000 {
+            let birds = random_line(&mut rng, 4);
+            let line = Line::from_birds(&birds);
+            assert_eq!(line.birds().collect::<Vec<Bird>>(), birds);
+            assert_eq!(line.counts(), BirdCounts::from_birds(&birds));
+            assert_eq!(line.len(), birds.len() as i32);
+        }
+    }
+
+    #[test]
+    fn extend_matches_inserting_in_order() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..1000 {
+            let birds = random_line(&mut rng, 4);
+            let new_birds = random_line(&mut rng, 4);
+            let left = rng.gen_bool(0.5);
+
+            let mut line = Line::from_birds(&birds);
+            line.extend(&new_birds, left);
+
+            let mut reference = birds.clone();
+            if left {
+                for (bird_idx, bird) in new_birds.iter().enumerate() {
+                    reference.insert(bird_idx, *bird);
                 }
             } else {
-                return false;
+                reference.extend(new_birds.iter());
+            }
+            assert_eq!(line.birds().collect::<Vec<Bird>>(), reference);
+        }
+    }
+
+    #[test]
+    fn play_matches_reference() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for species in 2..=8 {
+            for _ in 0..5000 {
+                let birds = random_line(&mut rng, species);
+                let bird = random_bird(&mut rng, species);
+                let count = rng.gen_range(1..5);
+                let left = rng.gen_bool(0.5);
+
+                let mut line = Line::from_birds(&birds);
+                let mut reference = VecLine(birds.clone());
+                let sandwiched = line.play(bird, count, left);
+                assert_eq!(sandwiched, reference.play(bird, count, left), "{:?} {:?}x{} left={}", birds, bird, count, left);
+                assert_eq!(line.birds().collect::<Vec<Bird>>(), reference.0);
+                assert_eq!(line, Line::from_birds(&reference.0));
+            }
+        }
+    }
+
+    #[test]
+    fn draw_new_matches_reference() {
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0..5000 {
+            let birds = random_line(&mut rng, 3);
+            let bird = random_bird(&mut rng, 3);
+            let left = rng.gen_bool(0.5);
+            let refill_left = rng.gen_bool(0.5);
+            let draw_pile: Vec<Bird> = (0..rng.gen_range(0..6)).map(|_| random_bird(&mut rng, 3)).collect();
+
+            let mut line = Line::from_birds(&birds);
+            let mut reference = VecLine(birds.clone());
+            if line.play(bird, 1, left).is_none() {
+                continue;
+            }
+            reference.play(bird, 1, left);
+            // A line is only refilled once it is down to one species, while the
+            // reference refills any line whose ends match.
+            if reference.0[0] == *reference.0.last().unwrap() && !line.needs_refill() {
+                continue;
+            }
+
+            let mut line_pile = draw_pile.clone();
+            let mut reference_pile = draw_pile.clone();
+            let mut line_discards = BirdCounts::new();
+            let mut reference_discards = BirdCounts::new();
+            assert_eq!(
+                line.draw_new(refill_left, &mut line_pile, &mut line_discards, &mut rng).is_some(),
+                reference.draw_new(refill_left, &mut reference_pile, &mut reference_discards, &mut rng),
+            );
+            assert_eq!(line.birds().collect::<Vec<Bird>>(), reference.0);
+            assert_eq!(line_pile, reference_pile);
+        }
+    }
+
+    /// The sandwich rule as stated: birds placed at one end capture everything between
+    /// them and the nearest bird of the same species further along the line.
+    fn model_play(line: &[Bird], bird: Bird, count: i32, left: bool) -> (Vec<Bird>, Option<BirdCounts>) {
+        let mut oriented: Vec<Bird> = if left { line.to_vec() } else { line.iter().rev().cloned().collect() };
+        for _ in 0..count {
+            oriented.insert(0, bird);
+        }
+        let mut captured = None;
+        if let Some(start) = oriented.iter().position(|x| *x != bird) {
+            if let Some(offset) = oriented[start..].iter().position(|x| *x == bird) {
+                captured = Some(BirdCounts::from_birds(&oriented[start..start + offset]));
+                oriented.drain(start..start + offset);
+            }
+        }
+        if !left {
+            oriented.reverse();
+        }
+        return (oriented, captured);
+    }
+
+    /// Random line of up to `species` species, including single-species lines.
+    fn any_line(rng: &mut StdRng, species: usize) -> Vec<Bird> {
+        let length = rng.gen_range(1..14);
+        return (0..length).map(|_| random_bird(rng, species)).collect();
+    }
+
+    #[test]
+    fn play_follows_sandwich_rule() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for _ in 0..20000 {
+            let species = rng.gen_range(1..=8);
+            let birds = any_line(&mut rng, species);
+            let bird = random_bird(&mut rng, species);
+            let count = rng.gen_range(1..6);
+            let left = rng.gen_bool(0.5);
+
+            let mut line = Line::from_birds(&birds);
+            let sandwiched = line.play(bird, count, left);
+            let (expected, expected_sandwiched) = model_play(&birds, bird, count, left);
+            assert_eq!(sandwiched, expected_sandwiched, "{:?} {:?}x{} left={}", birds, bird, count, left);
+            assert_eq!(line.birds().collect::<Vec<Bird>>(), expected);
+        }
+    }
+
+    #[test]
+    fn play_conserves_birds() {
+        let mut rng = StdRng::seed_from_u64(6);
+        for _ in 0..20000 {
+            let species = rng.gen_range(1..=8);
+            let birds = any_line(&mut rng, species);
+            let bird = random_bird(&mut rng, species);
+            let count = rng.gen_range(1..6);
+
+            let mut line = Line::from_birds(&birds);
+            let captured = line.play(bird, count, rng.gen_bool(0.5)).unwrap_or_default();
+            let mut played = BirdCounts::new();
+            played[bird] = count;
+            assert_eq!(BirdCounts::from_birds(&birds) + played, line.counts() + captured);
+            assert_eq!(line.len(), line.birds().count() as i32);
+        }
+    }
+
+    #[test]
+    fn sandwich_captures_from_played_side() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..20000 {
+            let species = rng.gen_range(2..=8);
+            let birds = random_line(&mut rng, species);
+            let bird = random_bird(&mut rng, species);
+            let count = rng.gen_range(1..6);
+            let left = rng.gen_bool(0.5);
+
+            let mut line = Line::from_birds(&birds);
+            let Some(captured) = line.play(bird, count, left) else {
+                continue;
+            };
+            let after: Vec<Bird> = line.birds().collect();
+            assert!(captured.total() > 0);
+            assert_eq!(captured[bird], 0, "captured the played species");
+            if left {
+                assert_eq!(after[..count as usize], vec![bird; count as usize][..]);
+                assert_eq!(after.last(), birds.last(), "far end changed");
+            } else {
+                assert_eq!(after[after.len() - count as usize..], vec![bird; count as usize][..]);
+                assert_eq!(after.first(), birds.first(), "far end changed");
+            }
+        }
+    }
+
+    #[test]
+    fn single_species_line_never_sandwiches() {
+        for bird in Bird::iter() {
+            for length in 1..6 {
+                for left in [true, false] {
+                    let mut line = Line::from_birds(&vec![bird; length]);
+                    assert_eq!(line.play(bird, 2, left), None);
+                    assert_eq!(line.len(), length as i32 + 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn refill_keeps_lines_whose_ends_match() {
+        let mut rng = StdRng::seed_from_u64(9);
+        for (birds, kept) in [("PPPOP", true), ("OPO", true), ("PPPP", false), ("D", false)] {
+            let birds = Bird::from_string(birds).unwrap();
+            for left in [true, false] {
+                let mut draw_pile = Bird::from_string("WWO").unwrap();
+                let mut discards = BirdCounts::new();
+                let mut line = Line::from_birds(&birds);
+                let drawn = line.draw_new(left, &mut draw_pile, &mut discards, &mut rng).unwrap();
+                assert_eq!(drawn.is_empty(), kept, "{:?}", birds);
+                assert_eq!(line.counts().species(), 2.max(BirdCounts::from_birds(&birds).species()));
+            }
+        }
+    }
+
+    #[test]
+    fn refill_leaves_two_species() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for _ in 0..20000 {
+            let species = rng.gen_range(1..=4);
+            let birds = any_line(&mut rng, species);
+            let left = rng.gen_bool(0.5);
+            let mut draw_pile: Vec<Bird> = (0..rng.gen_range(0..8)).map(|_| random_bird(&mut rng, 8)).collect();
+            let pile_before = BirdCounts::from_birds(&draw_pile);
+
+            let mut line = Line::from_birds(&birds);
+            let mut discards = BirdCounts::new();
+            let single_species = BirdCounts::from_birds(&birds).species() == 1;
+            let refill = line.draw_new(left, &mut draw_pile, &mut discards, &mut rng);
+            let refilled = refill.is_some();
+            let after: Vec<Bird> = line.birds().collect();
+            let drawn = after.len() - birds.len();
+            if let Some(refill) = refill {
+                let mut replayed = Line::from_birds(&birds);
+                replayed.extend(&refill, left);
+                assert_eq!(replayed, line, "refill birds do not replay onto the line");
+            }
+
+            assert_eq!(line.counts() + BirdCounts::from_birds(&draw_pile), BirdCounts::from_birds(&birds) + pile_before);
+            if !single_species {
+                assert!(refilled);
+                assert_eq!(after, birds, "refilled a line that already had two species");
+                continue;
+            }
+            if !refilled {
+                assert!(draw_pile.is_empty());
+                assert_eq!(line.counts().species(), 1);
+                continue;
             }
+            assert_eq!(line.counts().species(), 2);
+            let added = if left { &after[..drawn] } else { &after[birds.len()..] };
+            let newest = if left { added[0] } else { added[drawn - 1] };
+            assert_ne!(newest, birds[0], "kept drawing after a second species appeared");
+            assert_eq!(added.iter().filter(|x| **x != birds[0]).count(), 1);
         }
-        return true;
     }
 }
@@ -1,13 +1,16 @@
-use std::collections::HashMap;
-use rand::seq::index::{sample, sample_weighted};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use rand::{Rng, thread_rng};
+use rand::{Rng, SeedableRng, thread_rng};
 use crate::bird::Bird;
+use crate::bird_counts::BirdCounts;
+use crate::deal_sampler::{Deal, DealSampler};
+use crate::event::GameEvent;
 use crate::line::Line;
 use crate::{LINES, STARTING_CARDS_HAND};
-use crate::player::{MaterializedPlayer, Player};
+use crate::player::MaterializedPlayer;
+use crate::search_tree::{PlyMove, MAX_DEPTH};
 use crate::state::CubirdsState;
-use crate::utilities::weighted_choice;
+use crate::transposition::position_hash;
 
 pub trait SimplifiableMove {
     fn simplified(&self) -> String;
@@ -49,212 +52,571 @@ impl SimplifiableMove for LineMove {
     }
 }
 
+impl LineMove {
+    /// The first move, by line and then left before right, that reaches the same
+    /// position as this one on `board`: the same birds played onto an identical line
+    /// from the same end, or onto a mirrored one from the other end.
+    pub fn canonical(&self, board: &[Line; LINES]) -> LineMove {
+        let facing = |line: usize, left: bool| if left { board[line].clone() } else { board[line].reversed() };
+        let played = facing(self.line, self.left);
+        for line in 0..LINES {
+            for left in [true, false] {
+                if facing(line, left) == played {
+                    return LineMove{line, left, ..self.clone()};
+                }
+            }
+        }
+        return self.clone();
+    }
+}
+
+/// Where in a turn a position sits: before the birds are placed, after a play that
+/// sandwiched nothing (so the player may still draw), or before flying a flock.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TurnPhase {
+    Play,
+    Draw(LineMove),
+    Flock,
+}
+
 #[derive(Debug, Clone)]
 pub struct MaterializedCubirdsState {
     pub board: [Line; LINES],
     pub players: Vec<MaterializedPlayer>,
     pub player_idx: i32,
     pub draw_pile: Vec<Bird>,
-    pub discard_pile: HashMap<Bird, i32>,
+    pub discard_pile: BirdCounts,
     pub turn: usize,
+    pub rng: StdRng,
+    /// When set, every event of the game is appended here with nothing hidden.
+    pub log: Option<Vec<GameEvent>>,
 }
 
 impl MaterializedCubirdsState {
-    fn sample_pile(pile: &HashMap<Bird, i32>) -> Vec<Bird> {
-        let mut sampled = Vec::new();
-        for (bird, bird_count) in pile {
-            for _ in 0..*bird_count {
-                sampled.push(*bird);
-            }
-        }
-        sampled.shuffle(&mut thread_rng());
+    fn sample_pile<R: Rng>(pile: &BirdCounts, rng: &mut R) -> Vec<Bird> {
+        let mut sampled = pile.to_vec();
+        sampled.shuffle(rng);
         return sampled;
     }
 
-    pub fn sample_from(state: &CubirdsState) -> MaterializedCubirdsState {
-        let mut available_cards = state.all_available_cards();
+    pub fn sample_from(state: &CubirdsState, sampler: &DealSampler) -> MaterializedCubirdsState {
+        MaterializedCubirdsState::sample_with(state, sampler, StdRng::from_rng(thread_rng()).unwrap())
+    }
+
+    pub fn sample_with(state: &CubirdsState, sampler: &DealSampler, mut rng: StdRng) -> MaterializedCubirdsState {
+        let deal = sampler.sample(&mut rng);
+        return MaterializedCubirdsState::from_deal(state, deal, rng);
+    }
 
+    /// Puts the hidden cards of `deal` in place around the tracked state's public ones.
+    pub fn from_deal(state: &CubirdsState, deal: Deal, rng: StdRng) -> MaterializedCubirdsState {
         let mut players = Vec::new();
-        for player in &state.players {
+        for (player, cards) in state.players.iter().zip(deal.hands) {
             players.push(MaterializedPlayer{
-                collection: player.collection.clone(),
-                cards: player.cards.sample(&mut available_cards),
+                collection: player.collection,
+                cards,
             });
         }
 
-        let mut discarded = HashMap::new();
-        for discard in &state.discard_pile {
-            for (discarded_bird, discard_count) in discard.sample(&mut available_cards) {
-                *discarded.entry(discarded_bird).or_insert(0) += discard_count;
-            }
-        }
-
-        let deck = MaterializedCubirdsState::sample_pile(&available_cards);
-
         return MaterializedCubirdsState{
             board: state.board.clone(),
-            players: players,
+            players,
             player_idx: state.player_idx,
-            draw_pile: deck,
-            discard_pile: discarded,
+            draw_pile: deal.deck,
+            discard_pile: deal.discarded,
             turn: state.turn,
+            rng,
+            log: None,
         };
     }
 
-    pub fn draw(draw_pile: &mut Vec<Bird>, discard_pile: &mut HashMap<Bird, i32>) -> Option<Bird> {
-        if draw_pile.len() == 0 {
-            *draw_pile = MaterializedCubirdsState::sample_pile(&discard_pile);
-            *discard_pile = HashMap::new();
+    /// Deals a new game by the rules: four lines of three different species, then a
+    /// hand of `STARTING_CARDS_HAND` and one collected bird for each player.
+    pub fn deal(players: usize, mut rng: StdRng) -> Result<MaterializedCubirdsState, String> {
+        if !(2..=5).contains(&players) {
+            return Err(format!("Cubirds is played by 2 to 5 players, not {}.", players));
+        }
+
+        let mut deck = BirdCounts::full_deck().to_vec();
+        deck.shuffle(&mut rng);
+
+        let mut set_aside = Vec::new();
+        let board = std::array::from_fn(|_| {
+            let mut line = Vec::new();
+            while line.len() < 3 {
+                let bird = deck.pop().unwrap();
+                if line.contains(&bird) {
+                    set_aside.push(bird);
+                } else {
+                    line.push(bird);
+                }
+            }
+            return Line::from_birds(&line);
+        });
+        deck.extend(set_aside);
+        deck.shuffle(&mut rng);
+
+        let mut dealt = Vec::new();
+        for _ in 0..players {
+            let cards = BirdCounts::from_birds(&deck.split_off(deck.len() - STARTING_CARDS_HAND as usize));
+            dealt.push(MaterializedPlayer{
+                collection: BirdCounts::new(),
+                cards,
+            });
+        }
+        for player in &mut dealt {
+            player.collection[deck.pop().unwrap()] += 1;
+        }
+
+        return Ok(MaterializedCubirdsState{
+            board,
+            players: dealt,
+            player_idx: 0,
+            draw_pile: deck,
+            discard_pile: BirdCounts::new(),
+            turn: 0,
+            rng,
+            log: None,
+        });
+    }
+
+    pub fn draw<R: Rng>(draw_pile: &mut Vec<Bird>, discard_pile: &mut BirdCounts, rng: &mut R) -> Option<Bird> {
+        if draw_pile.is_empty() {
+            *draw_pile = MaterializedCubirdsState::sample_pile(discard_pile, rng);
+            *discard_pile = BirdCounts::new();
         }
 
         return draw_pile.pop();
     }
 
+    fn record(&mut self, event: impl FnOnce(&MaterializedCubirdsState) -> GameEvent) {
+        if let Some(mut log) = self.log.take() {
+            log.push(event(self));
+            self.log = Some(log);
+        }
+    }
+
     fn reset(&mut self) -> bool {
+        self.record(|_| GameEvent::Reset);
         for player in &self.players {
-            for (discarded, discard_count) in &player.cards {
-                *self.discard_pile.entry(*discarded).or_insert(0) += *discard_count;
-            }
+            self.discard_pile += player.cards;
         }
 
         for player in &mut self.players {
-            player.cards = HashMap::new();
+            player.cards = BirdCounts::new();
             for _ in 0..STARTING_CARDS_HAND {
-                if let Some(drawn) = MaterializedCubirdsState::draw(&mut self.draw_pile, &mut self.discard_pile) {
-                    *player.cards.entry(drawn).or_insert(0) += 1;
+                if let Some(drawn) = MaterializedCubirdsState::draw(&mut self.draw_pile, &mut self.discard_pile, &mut self.rng) {
+                    player.cards[drawn] += 1;
                 } else {
                     return false;
                 }
             }
         }
 
+        for player in 0..self.players.len() {
+            self.record(|x| GameEvent::Hand{player, birds: x.players[player].cards.to_vec()});
+        }
         return true;
     }
 
-    pub fn random_play(&mut self) -> Option<LineMove> {
-        let mut player = &mut self.players[self.turn];
+    pub fn random_play(&mut self) -> Option<(LineMove, FlockMove)> {
+        let bird = self.players[self.turn].cards.species_choice(&mut self.rng)?;
+        let line = self.rng.gen_range(0..LINES);
+        let direction = self.rng.gen_range(0..2) == 0;
 
-        let available_birds: Vec<Bird> = player.cards.keys().cloned().collect();
-        let bird_idx = thread_rng().gen_range(0..available_birds.len());
-        let bird = available_birds[bird_idx];
-        let bird_count = player.cards[&bird];
-        player.cards.remove(&bird);
+        let line_move = self.place(LineMove{line, bird, left: direction, draw: false}, None)?;
+        let flock_move = self.random_flock_play();
+        if !self.end_turn() {
+            return None;
+        }
 
-        let line = thread_rng().gen_range(0..LINES);
-        let direction = thread_rng().gen_range(0..2) == 0;
+        return Some((line_move, flock_move));
+    }
 
-        let mut retval = LineMove{
-            line: line,
-            bird: bird,
-            left: direction,
-            draw: false,
-        };
+    /// Plays every bird of `line_move`'s species from the mover's hand. If nothing is
+    /// sandwiched the player then draws as `draw` says, or at random without it.
+    fn place(&mut self, mut line_move: LineMove, draw: Option<bool>) -> Option<LineMove> {
+        let LineMove{line, bird, left, ..} = line_move;
+        let player = &mut self.players[self.turn];
+        let bird_count = player.cards.remove(bird);
+        if bird_count == 0 {
+            return None;
+        }
 
-        if let Some(sandwiched) = self.board[line].play(bird, bird_count, direction) {
-            let direction = thread_rng().gen_range(0..2) == 0;
-            if !self.board[line].draw_new(direction, &mut self.draw_pile, &mut self.discard_pile) {
-                return None;
-            }
-            for (bird, bird_count) in sandwiched {
-                *player.cards.entry(bird).or_insert(0) += bird_count;
-            }
+        line_move.draw = false;
+        if let Some(sandwiched) = self.board[line].play(bird, bird_count, left) {
+            let direction = self.rng.gen_range(0..2) == 0;
+            let refill = self.board[line].draw_new(direction, &mut self.draw_pile, &mut self.discard_pile, &mut self.rng)?;
+            player.cards += sandwiched;
+            self.record(|x| GameEvent::Play{
+                player: x.turn,
+                bird,
+                count: bird_count,
+                line,
+                left,
+                refill: if refill.is_empty() { None } else { Some((refill, direction)) },
+            });
         } else {
-            let should_draw = thread_rng().gen_range(0..2) == 0;
-            if should_draw {
-                retval.draw = true;
-                for _ in 0..2 {
-                    if let Some(drawn) = MaterializedCubirdsState::draw(&mut self.draw_pile, &mut self.discard_pile) {
-                        *player.cards.entry(drawn).or_insert(0) += 1;
-                    } else {
-                        return None;
-                    }
-                }
-            } else if player.cards.keys().len() == 0 {
-                if !self.reset() {
-                    return None;
-                }
-            }
+            self.record(|x| GameEvent::Play{player: x.turn, bird, count: bird_count, line, left, refill: None});
+            line_move = match draw {
+                Some(draw) => self.finish_play(line_move, draw)?,
+                None => self.random_draw(line_move)?,
+            };
         }
+        return Some(line_move);
+    }
 
-        let _ = self.random_flock_play();
-        player = &mut self.players[self.turn];
+    /// Decides whether to draw after `line_move` placed birds without a sandwich.
+    fn random_draw(&mut self, line_move: LineMove) -> Option<LineMove> {
+        let should_draw = self.rng.gen_range(0..2) == 0;
+        return self.finish_play(line_move, should_draw);
+    }
 
-        if let Some(reset_success) = self.determine_reset() {
-            if reset_success {
-                return Some(retval);
+    /// Draws two cards if `should_draw`, after `line_move` placed birds without a sandwich.
+    fn finish_play(&mut self, mut line_move: LineMove, should_draw: bool) -> Option<LineMove> {
+        let player = &mut self.players[self.turn];
+        if should_draw {
+            line_move.draw = true;
+            let mut drawn = Vec::new();
+            for _ in 0..2 {
+                let bird = MaterializedCubirdsState::draw(&mut self.draw_pile, &mut self.discard_pile, &mut self.rng)?;
+                player.cards[bird] += 1;
+                drawn.push(bird);
             }
-            return None;
+            self.record(|x| GameEvent::Draw{player: x.turn, birds: Some((drawn[0], drawn[1]))});
         }
 
-        return Some(retval);
+        return Some(line_move);
     }
 
     fn random_flock_play(&mut self) -> FlockMove {
-        let mut player = &mut self.players[self.turn];
+        let flockable = self.players[self.turn].flockable();
+        let flock_idx = self.rng.gen_range(0..(flockable.len() + 1));
+        return self.fly(flockable.get(flock_idx).copied());
+    }
 
-        let flockable = player.flockable();
-        let flock_idx = thread_rng().gen_range(0..(flockable.len() + 1));
-        if flock_idx != flockable.len() {
-            player.fly_home(flockable[flock_idx], &mut self.discard_pile);
+    /// Flies the mover's flock of `bird`, if any and if they hold enough for one.
+    fn fly(&mut self, bird: Option<Bird>) -> FlockMove {
+        let player = &mut self.players[self.turn];
+        if let Some(bird) = bird.filter(|x| player.cards[*x] >= x.information().small) {
+            let small = player.cards[bird] < bird.information().large;
+            player.fly_home(bird, &mut self.discard_pile);
+            self.record(|x| GameEvent::Fly{
+                player: x.turn,
+                bird,
+                hand_size: x.players[x.turn].cards.total(),
+                small,
+            });
 
-            return FlockMove{bird: Some(flockable[flock_idx])};
+            return FlockMove{bird: Some(bird)};
         }
 
         return FlockMove{bird: None};
     }
 
-    fn determine_reset(&mut self) -> Option<bool> {
-        let player = &mut self.players[self.turn];
-        if player.cards.keys().len() == 0 {
-            return Some(self.reset());
-        }
+    /// Passes play on, redealing every hand first if the player ran out of cards.
+    fn end_turn(&mut self) -> bool {
+        let emptied = self.players[self.turn].cards.is_empty();
         self.turn = (self.turn + 1) % self.players.len();
-        return None;
+        if emptied {
+            return self.reset();
+        }
+        return true;
+    }
+
+    /// A hash of everything that decides how the game goes on. States that differ
+    /// only in the order of the lines, which end of a line is which, or the order of
+    /// the draw pile hash the same.
+    pub fn canonical_hash(&self) -> u64 {
+        let hands: Vec<BirdCounts> = self.players.iter().map(|x| x.cards).collect();
+        let collections: Vec<BirdCounts> = self.players.iter().map(|x| x.collection).collect();
+        return position_hash(&self.board, &hands, &collections, BirdCounts::from_birds(&self.draw_pile), self.discard_pile, self.turn);
+    }
+
+    /// Whether a collection wins the game: seven species, or two with three birds each.
+    pub fn collection_wins(collection: &BirdCounts) -> bool {
+        return collection.species() >= 7 || collection.iter().filter(|x| x.1 >= 3).count() >= 2;
     }
 
     pub fn player_win(&self) -> Option<i32> {
-        for (player_idx, player) in self.players.iter().enumerate() {
-            if player.collection.keys().len() >= 7 {
-                return Some(player_idx as i32);
-            }
-            let three_count: i32 = player.collection.values().map(|x| if *x >= 3 { 1 } else { 0 }).sum();
-            if three_count >= 2 {
-                return Some(player_idx as i32);
-            }
+        return self.players.iter().position(|x| MaterializedCubirdsState::collection_wins(&x.collection)).map(|x| x as i32);
+    }
+
+    /// The plies the mover can make next from `phase` of their turn.
+    pub fn first_plies(&self, phase: &TurnPhase) -> Vec<PlyMove> {
+        let player = &self.players[self.turn];
+        let mut plies = Vec::new();
+        match phase {
+            TurnPhase::Play => {
+                for (bird, count) in player.cards.iter().filter(|x| x.1 > 0) {
+                    for line in 0..LINES {
+                        for left in [true, false] {
+                            let sandwiches = self.board[line].clone().play(bird, count, left).is_some();
+                            for draw in [false, true] {
+                                if !(sandwiches && draw) {
+                                    plies.push(PlyMove::Line(LineMove{line, bird, left, draw}));
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            TurnPhase::Draw(line_move) => {
+                for draw in [false, true] {
+                    plies.push(PlyMove::Line(LineMove{draw, ..line_move.clone()}));
+                }
+            },
+            TurnPhase::Flock => {
+                plies.push(PlyMove::Flock(FlockMove{bird: None}));
+                plies.extend(player.flockable().into_iter().map(|x| PlyMove::Flock(FlockMove{bird: Some(x)})));
+            },
         }
-        return None;
+        return plies;
     }
 
-    fn complete_rollout(&mut self) -> Option<i32> {
-        let mut win = self.player_win();
-        while win == None {
-            let cmove = self.random_play();
-            if cmove == None {
+    /// Plays the game out at random from `phase` of the current turn, appending the
+    /// first `MAX_DEPTH` public moves to `path` and returning the winner.
+    pub fn rollout(&mut self, phase: &TurnPhase, path: &mut Vec<PlyMove>) -> Option<i32> {
+        return self.rollout_after(phase, None, path);
+    }
+
+    /// Like `rollout`, but the mover's next ply is `first`, one of `first_plies`.
+    /// Returns None if they can't make it in this deal.
+    pub fn forced_rollout(&mut self, phase: &TurnPhase, first: &PlyMove, path: &mut Vec<PlyMove>) -> Option<i32> {
+        return self.rollout_after(phase, Some(first), path);
+    }
+
+    fn rollout_after(&mut self, phase: &TurnPhase, first: Option<&PlyMove>, path: &mut Vec<PlyMove>) -> Option<i32> {
+        let (forced_line, forced_flock) = match first {
+            Some(PlyMove::Line(line_move)) => (Some(line_move), None),
+            Some(PlyMove::Flock(flock_move)) => (None, Some(flock_move)),
+            None => (None, None),
+        };
+        let mut in_turn = *phase != TurnPhase::Play;
+        match (phase, forced_line) {
+            (TurnPhase::Play, Some(forced)) => {
+                let line_move = self.place(forced.clone(), Some(forced.draw))?;
+                path.push(PlyMove::Line(line_move));
+                in_turn = true;
+            },
+            (TurnPhase::Draw(line_move), forced) => {
+                let line_move = match forced {
+                    Some(forced) => self.finish_play(line_move.clone(), forced.draw)?,
+                    None => self.random_draw(line_move.clone())?,
+                };
+                path.push(PlyMove::Line(line_move));
+            },
+            _ => {},
+        }
+        if in_turn {
+            let flock_move = match forced_flock {
+                Some(forced) => self.fly(forced.bird),
+                None => self.random_flock_play(),
+            };
+            path.push(PlyMove::Flock(flock_move));
+            if !self.end_turn() {
                 return None;
             }
+        }
+
+        let mut win = self.player_win();
+        while win.is_none() {
+            let (line_move, flock_move) = self.random_play()?;
+            if path.len() < MAX_DEPTH {
+                path.push(PlyMove::Line(line_move));
+                path.push(PlyMove::Flock(flock_move));
+            }
             win = self.player_win();
         }
         return win;
     }
+}
 
-    pub fn full_rollout(&mut self) -> Option<(LineMove, bool)> {
-        let first_move = self.random_play().unwrap();
-        if let Some(winner) = self.complete_rollout() {
-            return Some((first_move, winner == self.player_idx));
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use strum::IntoEnumIterator;
+    use crate::player::Player;
+    use super::*;
+
+    /// What `player_idx` knows at the start of the game.
+    fn tracked_view(game: &MaterializedCubirdsState, player_idx: usize) -> CubirdsState {
+        let mut state = CubirdsState::new();
+        state.board = game.board.clone();
+        for player in &game.players {
+            let mut tracked = Player::new();
+            tracked.collection = player.collection;
+            state.players.push(tracked);
         }
-        return None;
+        state.player_idx = player_idx as i32;
+        state.turn = game.turn;
+        state.draw_pile.total_cards = game.draw_pile.len() as i32;
+        state.set_birds(player_idx, &game.players[player_idx].cards.to_vec());
+        return state;
     }
 
-    pub fn flock_rollout(&mut self) -> Option<(FlockMove, bool)> {
-        let first_move = self.random_flock_play();
-        if let Some(x) = self.determine_reset() {
-            if !x {
-                return None;
+    /// The event as `player_idx` sees it: other players' draws and hands stay hidden.
+    fn observable(event: &GameEvent, player_idx: usize) -> Option<GameEvent> {
+        match event {
+            GameEvent::Draw{player, ..} if *player != player_idx => Some(GameEvent::Draw{player: *player, birds: None}),
+            GameEvent::Hand{player, ..} if *player != player_idx => None,
+            _ => Some(event.clone()),
+        }
+    }
+
+    fn assert_agree(tracked: &CubirdsState, game: &MaterializedCubirdsState, context: &str) {
+        assert_eq!(tracked.board, game.board, "board differs {}", context);
+        for (idx, (player, actual)) in tracked.players.iter().zip(game.players.iter()).enumerate() {
+            assert_eq!(player.collection, actual.collection, "player {} collection differs {}", idx, context);
+            assert_eq!(player.cards.total_cards, actual.cards.total(), "player {} card total differs {}", idx, context);
+            if idx == game.player_idx as usize {
+                assert_eq!(player.cards.known_cards, actual.cards, "our hand differs {}", context);
             }
+            for (bird, count) in player.cards.known_cards.iter() {
+                assert!(actual.cards[bird] >= count, "player {} known {:?} not in hand {}", idx, bird, context);
+            }
+            for bird in Bird::iter() {
+                let count = actual.cards[bird];
+                assert!(player.cards.min_cards[bird] <= count && count <= player.cards.max_cards[bird], "player {} holds {} {:?} outside its range {}", idx, count, bird, context);
+            }
+        }
+        assert_eq!(tracked.check_conservation(), Ok(()), "conservation fails {}", context);
+        let discarded: i32 = tracked.discard_pile.iter().map(|x| x.total_cards).sum();
+        assert_eq!(discarded, game.discard_pile.total(), "discard count differs {}", context);
+        for bird in Bird::iter() {
+            let lowest: i32 = tracked.discard_pile.iter().map(|x| x.min_cards[bird]).sum();
+            let highest: i32 = tracked.discard_pile.iter().map(|x| x.max_cards[bird].min(x.total_cards)).sum();
+            let count = game.discard_pile[bird];
+            assert!(lowest <= count && count <= highest, "{} {:?} discarded outside the ranges {}", count, bird, context);
         }
-        if let Some(winner) = self.complete_rollout() {
-            return Some((first_move, winner == self.player_idx));
+        assert_eq!(tracked.draw_pile.total_cards, game.draw_pile.len() as i32, "draw pile size differs {}", context);
+        let draw_pile = BirdCounts::from_birds(&game.draw_pile);
+        for (bird, count) in tracked.draw_pile.known_cards.iter() {
+            assert!(draw_pile[bird] >= count, "known {:?} not in draw pile {}", bird, context);
+        }
+    }
+
+    fn assert_samples_fit(tracked: &CubirdsState, game: &MaterializedCubirdsState, context: &str) {
+        let sampler = DealSampler::new(tracked).unwrap_or_else(|x| panic!("{} {}", x, context));
+        let mut samples = vec![MaterializedCubirdsState::sample_from(tracked, &sampler)];
+        for (deal, _) in sampler.deals().unwrap_or_default() {
+            samples.push(MaterializedCubirdsState::from_deal(tracked, deal, StdRng::seed_from_u64(0)));
+        }
+        for sampled in samples {
+            for (player, hand) in tracked.players.iter().zip(&sampled.players) {
+                assert_eq!(player.cards.total_cards, hand.cards.total(), "sampled hand size differs {}", context);
+                assert!(Bird::iter().all(|x| player.cards.min_cards[x] <= hand.cards[x] && hand.cards[x] <= player.cards.max_cards[x]), "sampled hand outside the ranges {}", context);
+            }
+            assert_eq!(sampled.draw_pile.len(), game.draw_pile.len(), "sampled draw pile differs {}", context);
+        }
+    }
+
+    #[test]
+    fn deals_by_the_rules() {
+        for players in 2..=5 {
+            for seed in 0..50 {
+                let game = MaterializedCubirdsState::deal(players, StdRng::seed_from_u64(seed)).unwrap();
+                for line in &game.board {
+                    assert_eq!(line.len(), 3);
+                    assert_eq!(line.counts().species(), 3);
+                }
+                let mut cards = BirdCounts::from_birds(&game.draw_pile) + game.discard_pile;
+                for player in &game.players {
+                    assert_eq!(player.cards.total(), STARTING_CARDS_HAND);
+                    assert_eq!(player.collection.total(), 1);
+                    cards += player.cards + player.collection;
+                }
+                for line in &game.board {
+                    cards += line.counts();
+                }
+                assert_eq!(game.players.len(), players);
+                assert_eq!(cards, BirdCounts::full_deck());
+
+                let again = MaterializedCubirdsState::deal(players, StdRng::seed_from_u64(seed)).unwrap();
+                assert_eq!(again.board, game.board);
+                assert_eq!(again.draw_pile, game.draw_pile);
+            }
+        }
+        assert!(MaterializedCubirdsState::deal(1, StdRng::seed_from_u64(0)).is_err());
+        assert!(MaterializedCubirdsState::deal(6, StdRng::seed_from_u64(0)).is_err());
+    }
+
+    #[test]
+    fn equates_moves_onto_matching_lines() {
+        let board = ["PWR", "RWP", "PWR", "OOD"].map(|x| Line::from_birds(&Bird::from_string(x).unwrap()));
+        let play = |line: usize, left: bool| LineMove{line, bird: Bird::OWL, left, draw: false};
+        assert_eq!(play(2, true).canonical(&board), play(0, true));
+        assert_eq!(play(1, false).canonical(&board), play(0, true));
+        assert_eq!(play(1, true).canonical(&board), play(0, false));
+        assert_eq!(play(3, true).canonical(&board), play(3, true));
+        assert_eq!(play(3, false).canonical(&board), play(3, false));
+        let draw = LineMove{draw: true, ..play(2, false)};
+        assert_eq!(draw.canonical(&board), LineMove{draw: true, ..play(0, false)});
+    }
+
+    #[test]
+    fn forced_rollouts_open_with_the_forced_ply() {
+        for seed in 0..20 {
+            let game = MaterializedCubirdsState::deal(3, StdRng::seed_from_u64(seed)).unwrap();
+            let mut flocking = game.clone();
+            flocking.players[game.turn].cards[Bird::DUCK] += Bird::DUCK.information().small;
+            let played = LineMove{line: 0, bird: Bird::DUCK, left: true, draw: false};
+            for (state, phase) in [(&game, TurnPhase::Play), (&flocking, TurnPhase::Flock), (&game, TurnPhase::Draw(played))] {
+                let plies = state.first_plies(&phase);
+                assert!(!plies.is_empty());
+                for ply in plies {
+                    let mut path = Vec::new();
+                    if state.clone().forced_rollout(&phase, &ply, &mut path).is_some() {
+                        assert_eq!(path[0], ply, "seed {}", seed);
+                    }
+                }
+            }
+            assert!(flocking.first_plies(&TurnPhase::Flock).contains(&PlyMove::Flock(FlockMove{bird: Some(Bird::DUCK)})));
+        }
+    }
+
+    #[test]
+    fn tracked_state_follows_random_games() {
+        for seed in 0..300 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let players = rng.gen_range(2..=5);
+            let mut game = MaterializedCubirdsState::deal(players, StdRng::seed_from_u64(rng.gen())).unwrap();
+            game.log = Some(Vec::new());
+            game.player_idx = rng.gen_range(0..players) as i32;
+            game.turn = rng.gen_range(0..players);
+            let player_idx = game.player_idx as usize;
+            let mut tracked = tracked_view(&game, player_idx);
+            let observer = (player_idx + 1) % players;
+            let mut observed = tracked_view(&game, observer);
+
+            let mut turns = 0;
+            while game.player_win().is_none() && turns < 500 {
+                let finished = game.random_play().is_none();
+                let events = game.log.replace(Vec::new()).unwrap();
+                if finished {
+                    break;
+                }
+                for event in &events {
+                    if let Some(event) = observable(event, player_idx) {
+                        tracked.apply(&event).unwrap();
+                    }
+                    if let Some(event) = observable(event, observer) {
+                        observed.apply(&event).unwrap();
+                    }
+                }
+                turns += 1;
+                let context = format!("after turn {} of seed {}: {:?}", turns, seed, events);
+                assert_agree(&tracked, &game, &context);
+                let mut public = observed.public_view();
+                public.player_idx = tracked.player_idx;
+                assert_eq!(tracked.public_view(), public, "public views differ {}", context);
+                assert_eq!(tracked.perspective(observer).check_conservation(), Ok(()), "perspective fails {}", context);
+                // Sampling is slow, so only some games check it.
+                if seed % 10 == 0 && turns % 5 == 0 {
+                    assert_samples_fit(&tracked, &game, &context);
+                }
+            }
         }
-        return None;
     }
 }
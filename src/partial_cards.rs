@@ -1,32 +1,49 @@
-use std::collections::{HashMap, HashSet};
-use crate::{Bird, STARTING_CARDS_HAND};
-use crate::utilities::weighted_choice;
+use crate::STARTING_CARDS_HAND;
+use crate::bird_counts::BirdCounts;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct PartialCards {
-    pub known_cards: HashMap<Bird, i32>,
-    pub blacklisted_cards: HashSet<Bird>,
+    pub known_cards: BirdCounts,
+    /// The part of `known_cards` every player has seen.
+    pub public_cards: BirdCounts,
+    /// Bounds on how many cards of each species are held, known or not, as every
+    /// player can deduce them. Upper bounds may be inferred rather than seen.
+    pub min_cards: BirdCounts,
+    pub max_cards: BirdCounts,
     pub total_cards: i32,
 }
 
 impl PartialCards {
     pub fn new() -> PartialCards {
+        PartialCards::unknown(STARTING_CARDS_HAND)
+    }
+
+    pub fn unknown(total_cards: i32) -> PartialCards {
         PartialCards{
-            known_cards: HashMap::new(),
-            blacklisted_cards: HashSet::new(),
-            total_cards: STARTING_CARDS_HAND,
+            known_cards: BirdCounts::new(),
+            public_cards: BirdCounts::new(),
+            min_cards: BirdCounts::new(),
+            max_cards: BirdCounts::uniform(total_cards),
+            total_cards,
         }
     }
 
-    pub fn sample(&self, available_cards: &mut HashMap<Bird, i32>) -> HashMap<Bird, i32> {
-        let known_card_count: i32 = self.known_cards.values().sum();
-        let unknown_cards = self.total_cards - known_card_count;
-        let mut sampled_cards = self.known_cards.clone();
-        for _ in 0..unknown_cards {
-            let choice = weighted_choice(&available_cards, Some(&self.blacklisted_cards)).unwrap();
-            *available_cards.get_mut(&choice).unwrap() -= 1;
-            *sampled_cards.entry(choice).or_insert(0) += 1;
+    /// Cards every player has seen.
+    pub fn revealed(cards: BirdCounts) -> PartialCards {
+        PartialCards{
+            known_cards: cards,
+            public_cards: cards,
+            min_cards: cards,
+            max_cards: cards,
+            total_cards: cards.total(),
+        }
+    }
+
+    /// Cards every player knows about: only the public ones count as known.
+    pub fn public(&self) -> PartialCards {
+        PartialCards{
+            known_cards: self.public_cards,
+            ..self.clone()
         }
-        return sampled_cards;
     }
 }
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use crate::materialized_state::{FlockMove, LineMove, SimplifiableMove};
+
+/// Plies recorded per rollout. Deep enough to cover a couple of full rounds.
+pub const MAX_DEPTH: usize = 24;
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum PlyMove {
+    Line(LineMove),
+    Flock(FlockMove),
+}
+
+impl SimplifiableMove for PlyMove {
+    fn simplified(&self) -> String {
+        match self {
+            PlyMove::Line(line_move) => line_move.simplified(),
+            PlyMove::Flock(flock_move) => flock_move.simplified(),
+        }
+    }
+}
+
+/// Rollout statistics keyed by the public moves that followed a position. Wins are
+/// summed per player, indexed by seat, out of the total `weight` of the rollouts.
+/// Each recorded rollout adds at most one new node.
+#[derive(Debug, Clone, Default)]
+pub struct SearchNode {
+    pub wins: Vec<f64>,
+    pub weight: f64,
+    pub visits: i32,
+    pub children: HashMap<PlyMove, SearchNode>,
+}
+
+impl SearchNode {
+    pub fn new() -> SearchNode {
+        SearchNode::default()
+    }
+
+    pub fn record(&mut self, path: &[PlyMove], winner: usize) {
+        self.record_weighted(path, winner, 1.0);
+    }
+
+    /// Records a rollout that counts for `weight` rollouts, such as one deal out of
+    /// all of them weighted by its probability.
+    pub fn record_weighted(&mut self, path: &[PlyMove], winner: usize, weight: f64) {
+        self.visits += 1;
+        self.weight += weight;
+        if self.wins.len() <= winner {
+            self.wins.resize(winner + 1, 0.0);
+        }
+        self.wins[winner] += weight;
+        if let Some((ply, rest)) = path.split_first() {
+            if let Some(child) = self.children.get_mut(ply) {
+                child.record_weighted(rest, winner, weight);
+            } else {
+                let mut child = SearchNode::new();
+                child.record_weighted(&[], winner, weight);
+                self.children.insert(ply.clone(), child);
+            }
+        }
+    }
+
+    pub fn descend(mut self, plies: &[PlyMove]) -> SearchNode {
+        for ply in plies {
+            self = self.children.remove(ply).unwrap_or_default();
+        }
+        return self;
+    }
+
+    pub fn wins_for(&self, player: usize) -> f64 {
+        self.wins.get(player).copied().unwrap_or(0.0)
+    }
+
+    /// How often `player` won, weighing each rollout by its weight.
+    pub fn win_rate(&self, player: usize) -> f64 {
+        if self.weight == 0.0 {
+            return 0.0;
+        }
+        return self.wins_for(player) / self.weight;
+    }
+
+    /// Each child's wins, weight and visits.
+    pub fn child_scores(&self) -> HashMap<PlyMove, (Vec<f64>, f64, i32)> {
+        self.children.iter().map(|(ply, child)| (ply.clone(), (child.wins.clone(), child.weight, child.visits))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bird::Bird;
+    use super::*;
+
+    #[test]
+    fn records_every_winner() {
+        let ply = PlyMove::Flock(FlockMove{bird: Some(Bird::OWL)});
+        let path = [ply.clone()];
+        let mut root = SearchNode::new();
+        root.record(&path, 2);
+        root.record(&path, 0);
+        root.record(&path, 2);
+
+        assert_eq!(root.wins, vec![1.0, 0.0, 2.0]);
+        let (wins, weight, visits) = root.child_scores()[&ply].clone();
+        assert_eq!((wins, weight, visits), (vec![1.0, 0.0, 2.0], 3.0, 3));
+        assert_eq!(root.descend(&[ply]).wins_for(1), 0.0);
+    }
+
+    #[test]
+    fn weighs_rollouts() {
+        let ply = PlyMove::Flock(FlockMove{bird: None});
+        let mut root = SearchNode::new();
+        root.record_weighted(std::slice::from_ref(&ply), 0, 0.75);
+        root.record_weighted(std::slice::from_ref(&ply), 1, 0.25);
+        root.record_weighted(&[], 1, 0.5);
+
+        assert_eq!(root.visits, 3);
+        assert_eq!(root.win_rate(0), 0.5);
+        assert_eq!(root.children[&ply].win_rate(0), 0.75);
+        assert_eq!(SearchNode::new().win_rate(0), 0.0);
+    }
+}
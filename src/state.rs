@@ -1,22 +1,30 @@
-use std::collections::{HashMap, HashSet};
-use std::io::stdin;
+use std::io::{BufRead, Write};
 use std::str::FromStr;
+use strum::IntoEnumIterator;
 use crate::bird::Bird;
+use crate::bird_counts::BirdCounts;
+use crate::event::GameEvent;
 use crate::line::Line;
 use crate::{LINES, STARTING_CARDS_HAND};
-use crate::materialized_state::MaterializedCubirdsState;
 use crate::player::Player;
-use strum::IntoEnumIterator;
 use crate::partial_cards::PartialCards;
-use crate::utilities::remove_from_hashmap;
 
-#[derive(Debug, Clone)]
+/// A three-player setup as `read_setup` reads it, where player 1 starts and we are
+/// player 0, for tests and fuzzing.
+pub const EXAMPLE_SETUP: &str = "3\n1\n0\nPPRROFWM\nP\nO\nD\nRRW\nPPO\nFMM\nMWW\n";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CubirdsState {
     pub board: [Line; LINES],
     pub players: Vec<Player>,
     pub player_idx: i32,
     pub discard_pile: Vec<PartialCards>,
-    pub total_discarded: i32,
+    /// Cards left to draw. Known cards are a lower bound on what is still in the pile.
+    pub draw_pile: PartialCards,
+    /// A line a sandwich left with one species, waiting for its refill.
+    pub pending_refill: Option<usize>,
+    /// Whether hands were redealt and ours is still to be entered.
+    pub pending_hand: bool,
     pub turn: usize,
 }
 
@@ -32,345 +40,507 @@ impl CubirdsState {
             players: Vec::new(),
             player_idx: 0,
             discard_pile: Vec::new(),
-            total_discarded: 0,
+            draw_pile: PartialCards::unknown(0),
+            pending_refill: None,
+            pending_hand: false,
             turn: 0,
         }
     }
 
-    pub fn all_available_cards(&self) -> HashMap<Bird, i32> {
-        let mut cards_available = HashMap::new();
-        for bird in Bird::iter() {
-            cards_available.insert(bird, bird.information().cards);
-        }
+    pub fn all_available_cards(&self) -> BirdCounts {
+        let mut cards_available = BirdCounts::full_deck();
         for player in &self.players {
-            for (bird, &count) in player.cards.known_cards.iter() {
-                *cards_available.get_mut(bird).unwrap() -= count;
-            }
-            for (bird, &count) in player.collection.iter() {
-                *cards_available.get_mut(bird).unwrap() -= count;
-            }
+            cards_available -= player.cards.known_cards;
+            cards_available -= player.collection;
         }
-        for line_idx in 0..LINES {
-            let line = &self.board[line_idx];
-            for bird in &line.0 {
-                *cards_available.get_mut(bird).unwrap() -= 1;
-            }
+        for line in &self.board {
+            cards_available -= line.counts();
         }
         for discard in &self.discard_pile {
-            for (discarded, discard_count) in &discard.known_cards {
-                *cards_available.get_mut(discarded).unwrap() -= *discard_count;
-            }
+            cards_available -= discard.known_cards;
         }
+        cards_available -= self.draw_pile.known_cards;
         return cards_available;
     }
 
-    fn get_single_bird() -> Bird {
-        let mut bird_string = String::new();
-        let _ = stdin().read_line(&mut bird_string);
-        bird_string.pop();
-
-        let bird = Bird::from_char(bird_string.chars().next().unwrap()).unwrap();
-        return bird;
+    fn read_setup_line<R: BufRead>(input: &mut R) -> Result<String, String> {
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) => return Err(String::from("Setup ended early.")),
+            Ok(_) => {},
+            Err(error) => return Err(error.to_string()),
+        }
+        return Ok(line.trim_end_matches(['\n', '\r']).to_string());
     }
 
-    fn get_multiple_birds() -> Vec<Bird> {
-        let mut hand_string = String::new();
-        let _ = stdin().read_line(&mut hand_string);
-        hand_string.pop();
-
-        let birds = Bird::from_string(hand_string.as_str()).unwrap();
-        return birds;
+    fn read_setup_number<R: BufRead>(input: &mut R) -> Result<usize, String> {
+        let line = CubirdsState::read_setup_line(input)?;
+        return usize::from_str(&line).map_err(|_| format!("Invalid number '{}'.", line));
     }
 
-    fn get_number() -> usize {
-        let mut number_string = String::new();
-        let _ = stdin().read_line(&mut number_string);
-        number_string.pop();
-
-        let number = usize::from_str(number_string.as_str()).unwrap();
-        return number;
+    fn read_setup_birds<R: BufRead>(input: &mut R) -> Result<Vec<Bird>, String> {
+        let line = CubirdsState::read_setup_line(input)?;
+        return Bird::from_string(&line).ok_or_else(|| format!("Invalid birds '{}'.", line));
     }
 
-    pub fn initial_state() -> CubirdsState {
+    /// Reads a game setup, one answer per line, writing the questions to `output`:
+    /// player count, first player, our seat, our hand, every collection, then the lines.
+    pub fn read_setup<R: BufRead, W: Write>(input: &mut R, output: &mut W) -> Result<CubirdsState, String> {
         let mut state = CubirdsState::new();
 
         {
-            println!("Number of players:");
-            let players = CubirdsState::get_number() as i32;
+            let _ = writeln!(output, "Number of players:");
+            let players = CubirdsState::read_setup_number(input)?;
+            if !(2..=5).contains(&players) {
+                return Err(format!("Cubirds is played by 2 to 5 players, not {}.", players));
+            }
             for _ in 0..players {
                 state.players.push(Player::new());
             }
         }
 
         {
-            println!("First player:");
-            state.turn = CubirdsState::get_number();
+            let _ = writeln!(output, "First player:");
+            state.turn = CubirdsState::read_setup_number(input)?;
+            if state.turn >= state.players.len() {
+                return Err(format!("There is no player {}.", state.turn));
+            }
         }
 
         {
-            println!("Main player:");
-            state.player_idx = CubirdsState::get_number() as i32;
+            let _ = writeln!(output, "Main player:");
+            let player_idx = CubirdsState::read_setup_number(input)?;
+            if player_idx >= state.players.len() {
+                return Err(format!("There is no player {}.", player_idx));
+            }
+            state.player_idx = player_idx as i32;
         }
 
         {
-            println!("Player initial hand:");
-            for bird in CubirdsState::get_multiple_birds() {
-                *state.players[state.player_idx as usize].cards.known_cards.entry(bird).or_insert(0) += 1;
+            let _ = writeln!(output, "Player initial hand:");
+            for bird in CubirdsState::read_setup_birds(input)? {
+                state.players[state.player_idx as usize].cards.known_cards[bird] += 1;
             }
         }
 
         {
             for index in 0..state.players.len() {
-                println!("Partial player {} initial collection:", index);
-                state.players[index].collection.insert(CubirdsState::get_single_bird(), 1);
+                let _ = writeln!(output, "Partial player {} initial collection:", index);
+                let birds = CubirdsState::read_setup_birds(input)?;
+                if birds.len() != 1 {
+                    return Err(format!("Player {} must start with exactly one bird.", index));
+                }
+                state.players[index].collection[birds[0]] = 1;
             }
         }
 
         {
             for index in 0..LINES {
-                println!("Line {}:", index);
-                state.board[index] = Line(CubirdsState::get_multiple_birds());
+                let _ = writeln!(output, "Line {}:", index);
+                state.board[index] = Line::from_birds(&CubirdsState::read_setup_birds(input)?);
             }
         }
 
-        return state;
+        let dealt: i32 = state.players.iter().map(|x| x.cards.total_cards + x.collection.total()).sum();
+        let on_board: i32 = state.board.iter().map(|x| x.len()).sum();
+        state.draw_pile.total_cards = BirdCounts::full_deck().total() - dealt - on_board;
+
+        state.check_conservation()?;
+        return Ok(state);
     }
 
-    pub fn play(&mut self, player_number: usize, bird: Bird, count: i32, line: usize, play_dir: bool, new_bird: Option<(Vec<Bird>, bool)>) {
-        let mut player = &mut self.players[player_number];
+    /// Checks that the tracked cards add up to the deck: no species is accounted for more
+    /// often than it exists, and the unknown cards are exactly what is left unaccounted for.
+    pub fn check_conservation(&self) -> Result<(), String> {
+        let available = self.all_available_cards();
+        for bird in Bird::iter() {
+            if available[bird] < 0 {
+                return Err(format!("{} more {:?} cards are tracked than exist.", -available[bird], bird));
+            }
+        }
 
-        player.cards.known_cards.remove(&bird);
-        player.cards.blacklisted_cards.insert(bird);
-        player.cards.total_cards -= count;
-        
-        if let Some(sandwiched) = self.board[line].play(bird, count, play_dir) {
-            for (sbird, sbird_count) in sandwiched {
-                *player.cards.known_cards.entry(sbird).or_insert(0) += sbird_count;
-                player.cards.total_cards += sbird_count;
-                player.cards.blacklisted_cards.remove(&sbird);
+        let mut unknown = 0;
+        for (index, player) in self.players.iter().enumerate() {
+            let hidden = player.cards.total_cards - player.cards.known_cards.total();
+            if hidden < 0 {
+                return Err(format!("Player {} holds fewer cards than are known.", index));
             }
+            unknown += hidden;
+        }
+        for discard in &self.discard_pile {
+            let hidden = discard.total_cards - discard.known_cards.total();
+            if hidden < 0 {
+                return Err(String::from("A discard holds fewer cards than are known."));
+            }
+            unknown += hidden;
+        }
+        let hidden = self.draw_pile.total_cards - self.draw_pile.known_cards.total();
+        if hidden < 0 {
+            return Err(String::from("The draw pile holds fewer cards than are known."));
+        }
+        unknown += hidden;
+        if unknown != available.total() {
+            return Err(format!("{} unknown cards are tracked but {} are unaccounted for.", unknown, available.total()));
+        }
+        return Ok(());
+    }
 
-            if let Some(new) = new_bird {
-                let (nb, nbd) = new;
-                if nbd {
-                    for (bird_idx, bird) in nb.iter().enumerate() {
-                        self.board[line].0.insert(bird_idx, *bird);
-                    }
-                } else {
-                    for bird in nb {
-                        self.board[line].0.push(bird);
+    /// Rejects events that name players or lines that don't exist, or that don't fit
+    /// the tracked hand of the player making them.
+    fn validate(&self, event: &GameEvent) -> Result<(), String> {
+        if let GameEvent::Refill{line, birds, left} = event {
+            if self.pending_refill != Some(*line) {
+                return Err(format!("Line {} isn't waiting for a refill.", line));
+            }
+            return CubirdsState::validate_refill(&self.board[*line], birds, *left);
+        }
+        if let Some(line) = self.pending_refill {
+            return Err(format!("Line {} must be refilled first.", line));
+        }
+        if self.pending_hand {
+            let our_hand = self.player_idx as usize;
+            match event {
+                GameEvent::Hand{player, birds} if *player == our_hand && birds.len() as i32 != self.players[our_hand].cards.total_cards => {
+                    return Err(format!("The new hand has {} cards.", self.players[our_hand].cards.total_cards));
+                },
+                GameEvent::Hand{..} => {},
+                _ => return Err(format!("Player {}'s new hand must be entered first.", our_hand)),
+            }
+        }
+
+        let player_number = match event {
+            GameEvent::Play{player, ..} | GameEvent::Draw{player, ..} | GameEvent::Fly{player, ..} | GameEvent::Hand{player, ..} => *player,
+            GameEvent::Reset | GameEvent::Refill{..} => return Ok(()),
+        };
+        let cards = &self.players.get(player_number).ok_or_else(|| format!("There is no player {}.", player_number))?.cards;
+
+        match event {
+            GameEvent::Play{bird, count, line, left, refill, ..} => {
+                if *line >= LINES {
+                    return Err(format!("There is no line {}.", line));
+                }
+                if *count < 1 || *count > cards.total_cards {
+                    return Err(format!("Player {} can't play {} birds.", player_number, count));
+                }
+                let held = cards.known_cards[*bird].max(cards.min_cards[*bird]);
+                if *count < held {
+                    return Err(format!("Player {} is known to hold {} {:?} cards.", player_number, held, bird));
+                }
+                if let Some((refill, refill_left)) = refill.as_ref().filter(|x| !x.0.is_empty()) {
+                    let mut played = self.board[*line].clone();
+                    if played.play(*bird, *count, *left).is_none() || !played.needs_refill() {
+                        return Err(format!("Line {} needs no refill after this play.", line));
                     }
+                    CubirdsState::validate_refill(&played, refill, *refill_left)?;
+                }
+            },
+            GameEvent::Fly{bird, hand_size, small, ..} => {
+                let flown_count = cards.total_cards - hand_size;
+                let required = if *small { bird.information().small } else { bird.information().large };
+                if *hand_size < 0 || flown_count < required {
+                    return Err(format!("Player {} can't fly a {} flock down to {} cards.", player_number, if *small { "small" } else { "large" }, hand_size));
+                }
+                if *small && flown_count >= bird.information().large {
+                    return Err(format!("Player {} flies {} {:?} cards, which makes a large flock.", player_number, flown_count, bird));
+                }
+                if flown_count < cards.known_cards[*bird].max(cards.min_cards[*bird]) || cards.known_cards.total() - cards.known_cards[*bird] > *hand_size {
+                    return Err(format!("Player {} can't keep {} cards after flying.", player_number, hand_size));
+                }
+            },
+            _ => {},
+        }
+        return Ok(());
+    }
+
+    /// A refill is drawn until a card differs from the line's species, so in draw order
+    /// it is some cards of that species followed by one of another.
+    fn validate_refill(line: &Line, birds: &[Bird], left: bool) -> Result<(), String> {
+        let species = line.birds().next();
+        let drawn: Vec<Bird> = if left { birds.iter().rev().copied().collect() } else { birds.to_vec() };
+        let Some((last, rest)) = drawn.split_last() else {
+            return Err(String::from("A refill needs at least one bird."));
+        };
+        if Some(*last) == species || rest.iter().any(|x| Some(*x) != species) {
+            return Err(format!("A refill draws {:?} until another species shows up.", species.unwrap()));
+        }
+        return Ok(());
+    }
+
+    /// Applies an observed event, returning whether it was a play that sandwiched birds.
+    pub fn apply(&mut self, event: &GameEvent) -> Result<bool, String> {
+        self.validate(event)?;
+        match event {
+            GameEvent::Play{player, bird, count, line, left, refill} => {
+                return self.play(*player, *bird, *count, *line, *left, refill.clone());
+            },
+            GameEvent::Draw{player, birds} => self.draw(*player, *birds)?,
+            GameEvent::Fly{player, bird, hand_size, small} => self.fly(*player, *bird, *hand_size, *small),
+            GameEvent::Refill{line, birds, left} => self.refill(*line, birds, *left)?,
+            GameEvent::Reset => self.reset()?,
+            GameEvent::Hand{player, birds} => self.set_birds(*player, birds),
+        }
+        return Ok(false);
+    }
+
+    /// Takes `count` cards off the draw pile, shuffling the discard pile back in
+    /// whenever it runs out. `birds` are the cards taken, if they were seen, and
+    /// `public` whether the whole table saw them.
+    fn take_from_draw_pile(&mut self, count: usize, birds: Option<&[Bird]>, public: bool) -> Result<(), String> {
+        // A seen card lowers only its own species' bound, an unseen one every species'.
+        let lower = |counts: &mut BirdCounts, seen: Option<Bird>| {
+            for bird in Bird::iter().filter(|x| seen.is_none_or(|seen| seen == *x)) {
+                counts[bird] = (counts[bird] - 1).max(0);
+            }
+        };
+
+        for index in 0..count {
+            if self.draw_pile.total_cards == 0 {
+                for discard in self.discard_pile.drain(..) {
+                    self.draw_pile.known_cards += discard.known_cards;
+                    self.draw_pile.public_cards += discard.public_cards;
+                    self.draw_pile.total_cards += discard.total_cards;
+                }
+                if self.draw_pile.total_cards == 0 {
+                    return Err(String::from("The draw pile and discard pile are empty."));
                 }
             }
+            self.draw_pile.total_cards -= 1;
+
+            let seen = birds.map(|x| x[index]);
+            lower(&mut self.draw_pile.known_cards, seen);
+            lower(&mut self.draw_pile.public_cards, if public { seen } else { None });
         }
+        return Ok(());
     }
 
-    pub fn draw(&mut self, player_number: usize, birds: Option<(Bird, Bird)>) {
-        let mut player = &mut self.players[player_number];
+    pub fn play(&mut self, player_number: usize, bird: Bird, count: i32, line: usize, play_dir: bool, new_bird: Option<(Vec<Bird>, bool)>) -> Result<bool, String> {
+        let player = &mut self.players[player_number];
+
+        player.cards.known_cards.remove(bird);
+        player.cards.public_cards.remove(bird);
+        player.cards.min_cards.remove(bird);
+        player.cards.max_cards.remove(bird);
+        player.cards.total_cards -= count;
 
-        player.cards.blacklisted_cards = HashSet::new();
+        if let Some(sandwiched) = self.board[line].play(bird, count, play_dir) {
+            player.cards.known_cards += sandwiched;
+            player.cards.public_cards += sandwiched;
+            player.cards.min_cards += sandwiched;
+            player.cards.max_cards += sandwiched;
+            player.cards.total_cards += sandwiched.total();
+
+            match new_bird {
+                Some((nb, nbd)) if !nb.is_empty() => self.refill(line, &nb, nbd)?,
+                _ => if self.board[line].needs_refill() {
+                    self.pending_refill = Some(line);
+                },
+            }
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+
+    pub fn refill(&mut self, line: usize, birds: &[Bird], left: bool) -> Result<(), String> {
+        // Refills are given in line order, so on the left they were drawn last first.
+        let drawn: Vec<Bird> = if left { birds.iter().rev().copied().collect() } else { birds.to_vec() };
+        self.take_from_draw_pile(drawn.len(), Some(&drawn), true)?;
+        self.board[line].extend(birds, left);
+        self.pending_refill = None;
+        return Ok(());
+    }
+
+    pub fn draw(&mut self, player_number: usize, birds: Option<(Bird, Bird)>) -> Result<(), String> {
+        let seen = birds.map(|x| [x.0, x.1]);
+        self.take_from_draw_pile(2, seen.as_ref().map(|x| &x[..]), false)?;
+
+        let player = &mut self.players[player_number];
+
+        player.cards.max_cards += BirdCounts::uniform(2);
         player.cards.total_cards += 2;
 
         if let Some(new_birds) = birds {
-            *player.cards.known_cards.entry(new_birds.0).or_insert(0) += 1;
-            *player.cards.known_cards.entry(new_birds.1).or_insert(0) += 1;
+            player.cards.known_cards[new_birds.0] += 1;
+            player.cards.known_cards[new_birds.1] += 1;
+        }
+        return Ok(());
+    }
+
+    /// Records that a player ended their turn without flying a flock, so they hold too
+    /// few cards of each species to fly one. Species they are known to have enough of
+    /// are left alone, since they chose not to.
+    pub fn decline_flocks(&mut self, player_number: usize) {
+        let cards = &mut self.players[player_number].cards;
+        for bird in Bird::iter() {
+            let small = bird.information().small;
+            if cards.min_cards[bird] < small {
+                cards.max_cards[bird] = cards.max_cards[bird].min(small - 1);
+            }
         }
     }
 
     pub fn fly(&mut self, player_number: usize, bird: Bird, new_total_cards: i32, flock_small: bool) {
-        let mut player = &mut self.players[player_number];
+        let player = &mut self.players[player_number];
 
         let flock_size = if flock_small { 1 } else { 2 };
         let flown_count = player.cards.total_cards - new_total_cards;
         let discarded = flown_count - flock_size;
 
-        remove_from_hashmap(&mut player.cards.known_cards, bird);
-        player.cards.blacklisted_cards.insert(bird);
-        player.cards.total_cards -= flown_count as i32;
-
-        *player.collection.entry(bird).or_insert(0) += flock_size;
+        player.cards.known_cards.remove(bird);
+        player.cards.public_cards.remove(bird);
+        player.cards.min_cards.remove(bird);
+        player.cards.max_cards.remove(bird);
+        player.cards.total_cards -= flown_count;
 
-        let mut discarded_cards = PartialCards{
-            known_cards: HashMap::new(),
-            blacklisted_cards: HashSet::new(),
-            total_cards: discarded as i32,
-        };
-        discarded_cards.known_cards.insert(bird, discarded);
+        player.collection[bird] += flock_size;
 
-        self.discard_pile.push(discarded_cards);
+        let mut discarded_cards = BirdCounts::new();
+        discarded_cards[bird] = discarded;
+        self.discard_pile.push(PartialCards::revealed(discarded_cards));
     }
 
-    pub fn reset(&mut self) {
+    /// Discards every hand and deals `STARTING_CARDS_HAND` unseen cards to each player.
+    pub fn reset(&mut self) -> Result<(), String> {
         for player in &mut self.players {
-            for (bird, bird_count) in &player.cards.known_cards {
-                let mut discarded_cards = PartialCards{
-                    known_cards: HashMap::new(),
-                    blacklisted_cards: HashSet::new(),
-                    total_cards: *bird_count,
-                };
-                discarded_cards.known_cards.insert(*bird, *bird_count);
-                self.discard_pile.push(discarded_cards);
-            }
-            let total_known: i32 = player.cards.known_cards.values().sum();
-            let unknown = player.cards.total_cards - total_known;
-            if unknown > 0 {
-                let mut discarded_cards = PartialCards{
-                    known_cards: HashMap::new(),
-                    blacklisted_cards: HashSet::new(),
-                    total_cards: unknown,
-                };
-                self.discard_pile.push(discarded_cards);
-            }
-            player.cards.known_cards = HashMap::new();
-            player.cards.blacklisted_cards = HashSet::new();
-            player.cards.total_cards = STARTING_CARDS_HAND;
+            let discarded_cards = std::mem::replace(&mut player.cards, PartialCards::new());
+            self.discard_pile.push(discarded_cards);
         }
+        self.pending_hand = true;
+        return self.take_from_draw_pile(self.players.len() * STARTING_CARDS_HAND as usize, None, false);
     }
 
-    pub fn set_birds(&mut self, player_number: usize, birds: &Vec<Bird>) {
-        let mut player = &mut self.players[player_number];
+    pub fn set_birds(&mut self, player_number: usize, birds: &[Bird]) {
+        let player = &mut self.players[player_number];
 
-        player.cards.known_cards = HashMap::new();
-        player.cards.blacklisted_cards = HashSet::new();
+        player.cards.known_cards = BirdCounts::from_birds(birds);
+        for bird in Bird::iter() {
+            player.cards.public_cards[bird] = player.cards.public_cards[bird].min(player.cards.known_cards[bird]);
+        }
         player.cards.total_cards = birds.len() as i32;
-        for bird in birds {
-            *player.cards.known_cards.entry(*bird).or_insert(0) += 1;
+        player.cards.min_cards = player.cards.public_cards;
+        player.cards.max_cards = BirdCounts::uniform(player.cards.total_cards);
+        if player_number == self.player_idx as usize {
+            self.pending_hand = false;
         }
     }
 
-    pub fn next_move(&mut self, pre_fly_home: &dyn Fn(&CubirdsState)) {
-        println!("Turn {}", self.turn);
+    /// The state as every player sees it, with only publicly revealed cards known.
+    pub fn public_view(&self) -> CubirdsState {
+        let mut view = self.clone();
+        for player in &mut view.players {
+            player.cards = player.cards.public();
+        }
+        view.discard_pile = self.discard_pile.iter().map(|x| x.public()).collect();
+        view.draw_pile = self.draw_pile.public();
+        return view;
+    }
 
-        let mut player = &mut self.players[self.turn];
-        let is_main = self.turn == (self.player_idx as usize);
+    /// The state as `seat` saw it: the public view plus what is known of their own
+    /// hand, with `seat` as the player to evaluate for.
+    pub fn perspective(&self, seat: usize) -> CubirdsState {
+        let mut view = self.public_view();
+        view.players[seat].cards.known_cards = self.players[seat].cards.known_cards;
+        view.player_idx = seat as i32;
+        return view;
+    }
+}
 
-        let determine_player_draw = |player: &mut Player| {
-            println!("Did player draw? (0Y/1N)");
-            let draw = CubirdsState::get_number() == 0;
+#[cfg(test)]
+mod tests {
+    use std::io::sink;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use super::*;
 
-            if draw {
-                if is_main {
-                    println!("Cards drawn:");
-                    let birds = CubirdsState::get_multiple_birds();
+    const SETUP: &str = "2\n0\n0\nPPRROFWM\nP\nO\nRRW\nPPO\nFMM\nMWW\n";
 
-                    for bird in birds {
-                        *player.cards.known_cards.entry(bird).or_insert(0) += 1;
-                        player.cards.total_cards += 1;
-                    }
-                } else {
-                    player.cards.blacklisted_cards = HashSet::new();
-                    player.cards.total_cards += 2;
-                }
-            }
-            
-            return draw;
-        };
-
-        println!("Bird placed:");
-        let bird = CubirdsState::get_single_bird();
-        println!("Bird count:");
-        let bird_count = CubirdsState::get_number() as i32;
-        println!("Line:");
-        let line = CubirdsState::get_number();
-        println!("Direction: (0L/1R)");
-        let direction = CubirdsState::get_number() == 0;
-
-        remove_from_hashmap(&mut player.cards.known_cards, bird);
-        player.cards.blacklisted_cards.insert(bird);
-        player.cards.total_cards -= bird_count;
-
-        if let Some(sandwiched) = self.board[line].play(bird, bird_count, direction) {
-            println!("Birds new on line:");
-            let new_birds = CubirdsState::get_multiple_birds();
-            println!("New birds direction: (0L/1R)");
-            let new_direction = CubirdsState::get_number() == 0;
-
-            if new_direction {
-                for (bird_idx, bird) in new_birds.iter().enumerate() {
-                    self.board[line].0.insert(bird_idx, *bird);
-                }
-            } else {
-                for bird in new_birds {
-                    self.board[line].0.push(bird);
-                }
-            }
-
-            for (sbird, sbird_count) in sandwiched {
-                *player.cards.known_cards.entry(sbird).or_insert(0) += sbird_count;
-                player.cards.total_cards += sbird_count;
-                player.cards.blacklisted_cards.remove(&sbird);
-            }
-        } else {
-            player = &mut self.players[self.turn];
-            if !determine_player_draw(&mut player) && player.cards.total_cards == 0 {
-                for player in &mut self.players {
-                    player.cards.known_cards = HashMap::new();
-                    player.cards.blacklisted_cards = HashSet::new();
-                    player.cards.total_cards = STARTING_CARDS_HAND;
-                }
+    fn read(setup: &str) -> Result<CubirdsState, String> {
+        return CubirdsState::read_setup(&mut setup.as_bytes(), &mut sink());
+    }
 
-                println!("Player hand:");
-                for bird in CubirdsState::get_multiple_birds() {
-                    *self.players[self.player_idx as usize].cards.known_cards.entry(bird).or_insert(0) += 1;
-                }
+    #[test]
+    fn reads_setup() {
+        let state = read(SETUP).unwrap();
+        assert_eq!(state.players.len(), 2);
+        assert_eq!(state.players[0].cards.known_cards, BirdCounts::from_birds(&Bird::from_string("PPRROFWM").unwrap()));
+        assert_eq!(state.players[1].collection, BirdCounts::from_birds(&[Bird::OWL]));
+        assert_eq!(state.board[3], Line::from_birds(&Bird::from_string("MWW").unwrap()));
+        assert_eq!(state.draw_pile.total_cards, 110 - 12 - 2 * STARTING_CARDS_HAND - 2);
+    }
 
-                return;
-            }
+    #[test]
+    fn rejects_truncated_setup() {
+        let lines: Vec<&str> = SETUP.lines().collect();
+        for end in 0..lines.len() {
+            assert!(read(&lines[..end].join("\n")).is_err());
         }
+    }
 
-        pre_fly_home(self);
-        player = &mut self.players[self.turn];
-
-        println!("Did player fly home? (0Y/1N)");
-        let flew_home = CubirdsState::get_number() == 0;
-        if flew_home {
-            println!("Bird flown home:");
-            let bird_flown = CubirdsState::get_single_bird();
-
-            println!("Flock size: (0S/1L)");
-            let flock_small = CubirdsState::get_number() == 0;
-            let flock_size = if flock_small { 1 } else { 2 };
-
-            println!("New discard size:");
-            let discard_size = CubirdsState::get_number();
-
-            let old_discard_size = self.discard_pile.iter().map(|x| x.total_cards).sum::<i32>() as usize;
-            let new_discarded = discard_size - old_discard_size;
-            let birds_required = (if flock_small { bird_flown.information().small } else { bird_flown.information().large } - flock_size) as usize;
-            let flown_count = new_discarded + birds_required;
+    #[test]
+    fn rejects_impossible_setup() {
+        assert!(read("6\n0\n0\nP\nP\nP\nP\nP\nP\nP\nR\nR\nR\nR\n").is_err());
+        assert!(read("2\n2\n0\nP\nP\nO\nR\nR\nR\nR\n").is_err());
+        assert!(read("2\n0\n0\nPPPPPPPPPPPP\nP\nO\nRRW\nPPO\nFMM\nMWW\n").is_err());
+        assert!(read("2\n0\n0\nP\nPO\nO\nRRW\nPPO\nFMM\nMWW\n").is_err());
+    }
 
-            remove_from_hashmap(&mut player.cards.known_cards, bird_flown);
-            player.cards.blacklisted_cards.insert(bird_flown);
-            player.cards.total_cards -= flown_count as i32;
+    fn play(player: usize, bird: Bird, line: usize, refill: Option<(&str, bool)>) -> GameEvent {
+        GameEvent::Play{
+            player,
+            bird,
+            count: 1,
+            line,
+            left: true,
+            refill: refill.map(|(birds, left)| (Bird::from_string(birds).unwrap(), left)),
+        }
+    }
 
-            *player.collection.entry(bird_flown).or_insert(0) += flock_size;
+    #[test]
+    fn sandwich_waits_for_refill() {
+        let mut state = read(SETUP).unwrap();
+        let draw_pile = state.draw_pile.total_cards;
+
+        assert!(state.apply(&play(1, Bird::WARBLER, 0, None)).unwrap());
+        assert_eq!(state.pending_refill, Some(0));
+        assert!(state.apply(&GameEvent::Draw{player: 1, birds: None}).is_err());
+        assert!(state.apply(&GameEvent::Refill{line: 1, birds: vec![Bird::ROBIN], left: true}).is_err());
+        assert!(state.apply(&GameEvent::Refill{line: 0, birds: Bird::from_string("WR").unwrap(), left: true}).is_err());
+
+        state.apply(&GameEvent::Refill{line: 0, birds: Bird::from_string("RW").unwrap(), left: true}).unwrap();
+        assert_eq!(state.pending_refill, None);
+        assert_eq!(state.board[0], Line::from_birds(&Bird::from_string("RWWW").unwrap()));
+        assert_eq!(state.draw_pile.total_cards, draw_pile - 2);
+        assert_eq!(state.check_conservation(), Ok(()));
+    }
 
-            if new_discarded > 0 {
-                let mut discarded_cards = PartialCards{
-                    known_cards: HashMap::new(),
-                    blacklisted_cards: HashSet::new(),
-                    total_cards: new_discarded as i32,
-                };
+    #[test]
+    fn validates_inline_refill() {
+        let state = read(SETUP).unwrap();
+        assert!(state.clone().apply(&play(1, Bird::WARBLER, 0, Some(("WW", false)))).is_err());
+        assert!(state.clone().apply(&play(1, Bird::WARBLER, 1, Some(("RW", false)))).is_err());
 
-                self.discard_pile.push(discarded_cards);
-            }
-        }
+        let mut refilled = state.clone();
+        refilled.apply(&play(1, Bird::WARBLER, 0, Some(("WWR", false)))).unwrap();
+        assert_eq!(refilled.pending_refill, None);
+        assert_eq!(refilled.board[0], Line::from_birds(&Bird::from_string("WWWWR").unwrap()));
+    }
 
-        if player.cards.total_cards == 0 {
-            for player in &mut self.players {
-                player.cards.known_cards = HashMap::new();
-                player.cards.blacklisted_cards = HashSet::new();
-                player.cards.total_cards = STARTING_CARDS_HAND;
+    #[test]
+    fn mangled_setup_never_panics() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let alphabet = b"0123456789PWTFRMODX \n\r";
+        for _ in 0..2000 {
+            let mut setup = SETUP.as_bytes().to_vec();
+            for _ in 0..rng.gen_range(1..4) {
+                let idx = rng.gen_range(0..setup.len());
+                match rng.gen_range(0..3) {
+                    0 => setup[idx] = alphabet[rng.gen_range(0..alphabet.len())],
+                    1 => drop(setup.remove(idx)),
+                    _ => setup.insert(idx, alphabet[rng.gen_range(0..alphabet.len())]),
+                }
             }
-
-            println!("Player hand:");
-            for bird in CubirdsState::get_multiple_birds() {
-                *self.players[self.player_idx as usize].cards.known_cards.entry(bird).or_insert(0) += 1;
+            if let Ok(state) = read(&String::from_utf8(setup).unwrap()) {
+                assert_eq!(state.check_conservation(), Ok(()));
             }
-
-            return;
         }
-
-        self.turn = (self.turn + 1) % self.players.len();
     }
 }
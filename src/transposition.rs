@@ -0,0 +1,110 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use crate::bird_counts::BirdCounts;
+use crate::line::Line;
+
+/// Hashes `value` the same way on every run.
+pub fn hash_of(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    return hasher.finish();
+}
+
+/// A hash of the board that ignores the order of the lines and which end of each
+/// line is which, as neither changes how the game can go on.
+pub fn board_hash(board: &[Line]) -> u64 {
+    let mut lines: Vec<u64> = board.iter().map(|x| x.canonical_hash()).collect();
+    lines.sort_unstable();
+    return hash_of(lines);
+}
+
+/// A hash of everything that decides how the game goes on. Positions that differ only
+/// in the order of the lines or which end of a line is which hash the same.
+pub fn position_hash(board: &[Line], hands: &[BirdCounts], collections: &[BirdCounts], deck: BirdCounts, discard: BirdCounts, turn: usize) -> u64 {
+    return hash_of((board_hash(board), hands, collections, deck, discard, turn));
+}
+
+/// Values keyed by position hash, holding at most a fixed number of them. Each hash
+/// has one slot, and a new value replaces whatever held it.
+#[derive(Debug, Clone)]
+pub struct TranspositionTable<V> {
+    slots: Vec<Option<(u64, V)>>,
+}
+
+impl<V> TranspositionTable<V> {
+    /// A table of `capacity` slots, rounded up to a power of two.
+    pub fn new(capacity: usize) -> TranspositionTable<V> {
+        let mut slots = Vec::new();
+        slots.resize_with(capacity.max(1).next_power_of_two(), || None);
+        return TranspositionTable{slots};
+    }
+
+    fn slot(&self, hash: u64) -> usize {
+        (hash as usize) & (self.slots.len() - 1)
+    }
+
+    pub fn get(&self, hash: u64) -> Option<&V> {
+        match &self.slots[self.slot(hash)] {
+            Some((held, value)) if *held == hash => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, hash: u64, value: V) {
+        let slot = self.slot(hash);
+        self.slots[slot] = Some((hash, value));
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|x| x.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use crate::bird::Bird;
+    use crate::materialized_state::MaterializedCubirdsState;
+    use super::*;
+
+    #[test]
+    fn ignores_line_order_and_ends() {
+        let line = |x: &str| Line::from_birds(&Bird::from_string(x).unwrap());
+        let game = MaterializedCubirdsState::deal(3, StdRng::seed_from_u64(0)).unwrap();
+
+        let mut moved = game.clone();
+        moved.board.swap(0, 2);
+        moved.board[1] = Line::from_birds(&game.board[1].birds().collect::<Vec<Bird>>().into_iter().rev().collect::<Vec<Bird>>());
+        moved.draw_pile.reverse();
+        assert_eq!(moved.canonical_hash(), game.canonical_hash());
+
+        let mut changed = game.clone();
+        changed.turn = 1;
+        assert_ne!(changed.canonical_hash(), game.canonical_hash());
+        changed = game.clone();
+        changed.board[0].extend(&[Bird::OWL], true);
+        assert_ne!(changed.canonical_hash(), game.canonical_hash());
+
+        assert_eq!(board_hash(&[line("PWR"), line("OOD")]), board_hash(&[line("DOO"), line("RWP")]));
+        assert_ne!(board_hash(&[line("PWR"), line("OOD")]), board_hash(&[line("PRW"), line("OOD")]));
+        assert_ne!(board_hash(&[line("PWR"), line("OOD")]), board_hash(&[line("PWR"), line("OODO")]));
+    }
+
+    #[test]
+    fn holds_a_bounded_number_of_values() {
+        let mut table = TranspositionTable::new(5);
+        for hash in 0..100u64 {
+            table.insert(hash, hash * 2);
+        }
+        assert_eq!(table.len(), 8);
+        assert_eq!(table.get(99), Some(&198));
+        assert_eq!(table.get(3), None);
+        table.insert(3, 0);
+        assert_eq!(table.get(3), Some(&0));
+        assert_eq!(table.get(99), None);
+    }
+}